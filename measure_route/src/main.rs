@@ -23,5 +23,5 @@ async fn main() {
         println!("{:?}", ret);
     }
 
-    pinger.stop().await;
+    pinger.shutdown().await;
 }
@@ -1,28 +1,277 @@
-use tokio_ip_ping_request::ping;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio_ip_ping_request::ping::{self, UnreachableCode};
+use tokio_ip_ping_request::stream::{self, PingError, PingEvent, PingSummary, ProbeOptions};
+
+const USAGE: &str =
+    "Usage: ping [-c count] [-i interval] [-W timeout] [-t ttl] [-s size] [-M do|dont] target";
+
+struct Arguments {
+    target: IpAddr,
+    count: Option<u32>,
+    interval: Duration,
+    timeout: Duration,
+    ttl: u8,
+    // ICMP payload
+    size: u16,
+    dont_fragment: Option<bool>,
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or(format!("Missing {} value", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("Bad {} value: {}", flag, value))
+}
+
+fn parse_seconds(flag: &str, value: Option<String>) -> Result<Duration, String> {
+    let seconds: f64 = parse_value(flag, value)?;
+    if !seconds.is_finite() || seconds < 0. {
+        return Err(format!("Bad {} value: {}", flag, seconds));
+    }
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Arguments, String> {
+    let mut target = None;
+    let mut arguments = Arguments {
+        target: IpAddr::from([0, 0, 0, 0]),
+        count: None,
+        interval: Duration::from_secs(1),
+        timeout: Duration::from_secs(10),
+        ttl: 64,
+        size: 56,
+        dont_fragment: None,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" => arguments.count = Some(parse_value(&arg, args.next())?),
+            "-i" => arguments.interval = parse_seconds(&arg, args.next())?,
+            "-W" => arguments.timeout = parse_seconds(&arg, args.next())?,
+            "-t" => arguments.ttl = parse_value(&arg, args.next())?,
+            "-s" => arguments.size = parse_value(&arg, args.next())?,
+            "-M" => {
+                arguments.dont_fragment = match args.next().as_deref() {
+                    Some("do") => Some(true),
+                    Some("dont") => Some(false),
+                    _ => return Err("-M takes do or dont".to_string()),
+                }
+            }
+            _ if target.is_none() && !arg.starts_with('-') => {
+                target = Some(
+                    arg.parse()
+                        .map_err(|_| format!("Bad target address: {}", arg))?,
+                )
+            }
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+    arguments.target = target.ok_or("Missing target")?;
+    if arguments.interval.is_zero() {
+        return Err("The interval must not be null".to_string());
+    }
+    Ok(arguments)
+}
+
+fn unreachable_message(code: UnreachableCode) -> &'static str {
+    match code {
+        UnreachableCode::Network => "Destination Net Unreachable",
+        UnreachableCode::Host => "Destination Host Unreachable",
+        UnreachableCode::Protocol => "Destination Protocol Unreachable",
+        UnreachableCode::Port => "Destination Port Unreachable",
+        UnreachableCode::FragmentationNeeded => "Frag needed and DF set",
+        UnreachableCode::AdminProhibited => "Packet filtered",
+    }
+}
+
+fn print_error(seq: u32, error: &PingError) {
+    match error {
+        PingError::Timeout { .. } => println!("No answer for icmp_seq={}", seq),
+        PingError::TimeExceeded { addr, .. } => {
+            println!("From {} icmp_seq={} Time to live exceeded", addr, seq)
+        }
+        PingError::Unreachable {
+            responder,
+            code,
+            quoted,
+            ..
+        } => match quoted.next_hop_mtu {
+            Some(mtu) if *code == UnreachableCode::FragmentationNeeded => println!(
+                "From {} icmp_seq={} {} (mtu = {})",
+                responder,
+                seq,
+                unreachable_message(*code),
+                mtu
+            ),
+            _ => println!(
+                "From {} icmp_seq={} {}",
+                responder,
+                seq,
+                unreachable_message(*code)
+            ),
+        },
+        PingError::IcmpError {
+            responder,
+            ty,
+            code,
+            ..
+        } => println!(
+            "From {} icmp_seq={} ICMP type {} code {}",
+            responder, seq, ty.0, code.0
+        ),
+        PingError::InvalidEcho { reply, error } => println!(
+            "{} bytes from {}: icmp_seq={} wrong data: {:?}",
+            reply.payload_len + 8,
+            reply.responder,
+            seq,
+            error
+        ),
+        error => println!("icmp_seq={}: {:?}", seq, error),
+    }
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.
+}
+
+fn print_summary(target: IpAddr, summary: &PingSummary) {
+    println!();
+    println!("--- {} ping statistics ---", target);
+    print!(
+        "{} packets transmitted, {} received",
+        summary.transmitted, summary.received
+    );
+    if summary.duplicates > 0 {
+        print!(", +{} duplicates", summary.duplicates);
+    }
+    if summary.late > 0 {
+        print!(", +{} late", summary.late);
+    }
+    if summary.errors > 0 {
+        print!(", +{} errors", summary.errors);
+    }
+    println!(
+        ", {}% packet loss, time {}ms",
+        (summary.loss() * 1000.).round() / 1000.,
+        summary.time.as_millis()
+    );
+    if let Some(rtt) = summary.rtt {
+        println!(
+            "rtt min/avg/max/mdev = {:.3}/{:.3}/{:.3}/{:.3} ms",
+            milliseconds(rtt.min),
+            milliseconds(rtt.avg),
+            milliseconds(rtt.max),
+            milliseconds(rtt.mdev)
+        );
+    }
+}
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    let mut args = std::env::args().skip(1);
-    let target = args.next().unwrap();
-    let timeout: u64 = args.next().unwrap().parse().unwrap();
-    let timeout = std::time::Duration::from_secs(timeout);
-    let ttl: u8 = args.next().unwrap().parse().unwrap();
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
 
-    let icmp_pinger = ping::icmp::Pinger::new(64, 50).unwrap();
+    let pinger = match ping::icmp::Pinger::new(args.size.saturating_add(8), 64) {
+        Ok(pinger) => pinger,
+        Err(e) => {
+            eprintln!("ping: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let mut options = ProbeOptions::new().ttl(args.ttl);
+    if let Some(dont_fragment) = args.dont_fragment {
+        options = options.dont_fragment(dont_fragment);
+    }
+    let header_size = if args.target.is_ipv4() { 28 } else { 48 };
     println!(
-        "ICMP: {:?}",
-        icmp_pinger
-            .ping(target.parse().unwrap(), ttl, timeout, 0)
-            .await
+        "PING {} {}({}) bytes of data.",
+        args.target,
+        args.size,
+        args.size as usize + header_size
     );
-    icmp_pinger.stop().await;
 
-    let tcp_pinger = ping::tcp::Pinger::new(64, 50).unwrap();
-    println!(
-        "TCP: {:?}",
-        tcp_pinger
-            .ping(target.parse().unwrap(), ttl, timeout, 0)
-            .await
+    let mut stream = stream::ping_stream(
+        pinger.clone(),
+        args.target,
+        args.interval,
+        args.count,
+        options,
+        args.timeout,
     );
-    tcp_pinger.stop().await;
+    let mut interrupted = false;
+    let mut summary = None;
+    loop {
+        let event = tokio::select! {
+            event = stream.next() => event,
+            _ = tokio::signal::ctrl_c() => {
+                // The first Ctrl-C waits for the replies still expected, not the second one
+                if interrupted {
+                    std::process::exit(130);
+                }
+                interrupted = true;
+                stream.stop();
+                continue;
+            }
+        };
+        match event {
+            Some(PingEvent::Reply {
+                seq,
+                result: Ok(reply),
+            }) => {
+                let ttl = match reply.ip.reply {
+                    Some(header) => format!(" ttl={}", header.ttl),
+                    None => String::new(),
+                };
+                println!(
+                    "{} bytes from {}: icmp_seq={}{} time={:.3} ms",
+                    reply.payload_len + 8,
+                    reply.responder,
+                    seq,
+                    ttl,
+                    milliseconds(reply.rtt)
+                );
+            }
+            Some(PingEvent::Reply {
+                seq,
+                result: Err(error),
+            }) => print_error(seq, &error),
+            Some(PingEvent::Duplicate {
+                seq,
+                responder,
+                rtt,
+            }) => println!(
+                "From {}: icmp_seq={} time={:.3} ms (DUP!)",
+                responder,
+                seq,
+                milliseconds(rtt)
+            ),
+            Some(PingEvent::Late {
+                seq,
+                responder,
+                rtt,
+            }) => println!(
+                "From {}: icmp_seq={} time={:.3} ms (late)",
+                responder,
+                seq,
+                milliseconds(rtt)
+            ),
+            Some(PingEvent::Summary(s)) => summary = Some(s),
+            None => break,
+        }
+    }
+    pinger.shutdown().await;
+
+    let summary = match summary {
+        Some(summary) => summary,
+        None => std::process::exit(2),
+    };
+    print_summary(args.target, &summary);
+    if summary.received == 0 {
+        std::process::exit(1);
+    }
 }
@@ -2,19 +2,26 @@ mod configuration;
 mod cursor;
 mod deprecated;
 mod internet;
+mod metrics;
 
 use configuration::{Configuration, EncodableConfiguration};
 use futures::future::{select, Either};
 use internet::u32_to_ip;
+use metrics::ScanMetrics;
 use std::time::Duration;
 use tokio::io::AsyncWriteExt;
-use tokio::sync::{mpsc, oneshot};
-use tokio_ip_ping_request::ping::{self, icmp::Pinger};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_ip_ping_request::ping::{
+    self, icmp::Pinger, Diagnostic, PingError, PingReply, PingerMetrics, RateLimit,
+    UnreachableCode, RTT_BUCKETS,
+};
 
 enum Event {
     PingResult {
         index: u32,
-        latency: Option<Duration>,
+        reply: Option<PingReply>,
+        // Answered by an ICMP Destination Unreachable instead of timing out
+        unreachable: Option<UnreachableCode>,
     },
     LinkUp,
     LinkDown,
@@ -29,14 +36,16 @@ async fn run_pinger(
     timeout: Duration,
     response_tx: mpsc::Sender<Event>,
 ) {
-    let res = match pinger.ping(u32_to_ip(target), ttl, timeout, 0).await {
-        Ok(latency) => Some(latency),
-        _ => None,
+    let (reply, unreachable) = match pinger.ping(u32_to_ip(target).into(), ttl, timeout, 0).await {
+        Ok(reply) => (Some(reply), None),
+        Err(PingError::Unreachable { code, .. }) => (None, Some(code)),
+        _ => (None, None),
     };
     let _ = response_tx
         .send(Event::PingResult {
             index,
-            latency: res,
+            reply,
+            unreachable,
         })
         .await;
 }
@@ -50,6 +59,30 @@ pub fn encode_result(index: u32, duration: Duration) -> Box<[u8]> {
     .into()
 }
 
+fn format_metrics(metrics: &PingerMetrics) -> String {
+    let quantile = |q| match metrics.rtt.quantile(q) {
+        Some(bound) => format!("<={:?}", bound),
+        None if metrics.rtt.total() > 0 => format!(">{:?}", RTT_BUCKETS[RTT_BUCKETS.len() - 1]),
+        None => "-".to_string(),
+    };
+    format!(
+        "sent {}, matched {}, timeouts {}, send failures {}, unmatched {}, late {}, \
+        duplicates {}, in flight {}, queued {}/{}, rtt p50 {} p99 {}",
+        metrics.sent,
+        metrics.matched,
+        metrics.timeouts,
+        metrics.send_failures,
+        metrics.unmatched,
+        metrics.late,
+        metrics.duplicates,
+        metrics.in_flight,
+        metrics.queued,
+        metrics.command_capacity,
+        quantile(0.5),
+        quantile(0.99)
+    )
+}
+
 async fn monitor_cpu_load(
     refresh_rate: Duration,
     response_tx: mpsc::Sender<Event>,
@@ -72,6 +105,19 @@ async fn monitor_cpu_load(
     }
 }
 
+// Socket errors make every probe time out, which would otherwise look like a dead network
+async fn monitor_socket_errors(mut diagnostics: broadcast::Receiver<Diagnostic>) {
+    loop {
+        match diagnostics.recv().await {
+            Ok(Diagnostic::SocketError { message, .. }) => {
+                println!("Socket error: {}", message);
+            }
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
 async fn monitor_link(
     pinger: Pinger,
     conf: configuration::LinkStateMonitorConfiguration,
@@ -89,7 +135,7 @@ async fn monitor_link(
     let mut bad_state = false;
     loop {
         // Ping
-        let ping_future = pinger.ping(target, ttl, timeout, 0);
+        let ping_future = pinger.ping(target.into(), ttl, timeout, 0);
         tokio::pin!(ping_future);
         end = match select(end, ping_future).await {
             Either::Left(_) => break,
@@ -131,8 +177,20 @@ async fn monitor_link(
 pub async fn scan(conf: Configuration) {
     println!("Setup");
     // Build configuration bound objects
-    let pinger = Pinger::new(conf.ping.size, conf.ping.parallelism as usize).unwrap();
+    let pinger = Pinger::with_options(ping::icmp::PingerOptions {
+        size: conf.ping.size,
+        parallelism: conf.ping.parallelism as usize,
+        rate_limit: conf.ping.rate_limit.map(|limit| RateLimit {
+            pps: limit.pps,
+            burst: limit.burst,
+        }),
+        source: conf.ping.source,
+        device: conf.ping.device.clone(),
+        ..Default::default()
+    })
+    .unwrap();
     let mut cursor = conf.cursor.generate().unwrap();
+    tokio::spawn(monitor_socket_errors(pinger.diagnostics()));
 
     let mut parallelism_target = conf.ping.parallelism as usize - 1;
     let ttl = conf.ping.ttl;
@@ -190,20 +248,40 @@ pub async fn scan(conf: Configuration) {
     let mut cursor_done = false;
     let mut indices_since_last_checkpoint = 0;
     let mut link_down = false;
+    // Targets behind a firewall, and targets without a route
+    let mut filtered = 0;
+    let mut unreachable = 0;
     while let Some(event) = rx.recv().await {
         match event {
-            Event::PingResult { index, latency } => {
+            Event::PingResult {
+                index,
+                reply,
+                unreachable: code,
+            } => {
+                match code {
+                    Some(UnreachableCode::AdminProhibited) => filtered += 1,
+                    Some(_) => unreachable += 1,
+                    None => {}
+                }
+
                 // Print progress
                 i += 1;
                 let percent = 1000 * (i as u64) / conf.cursor.nb as u64;
                 if percent > (1000 * (i as u64 - 1) / conf.cursor.nb as u64) {
-                    println!("{:?}: Progress {}%", start.elapsed(), percent as f32 / 10.0,);
+                    println!(
+                        "{:?}: Progress {}% ({} filtered, {} unreachable) {}",
+                        start.elapsed(),
+                        percent as f32 / 10.0,
+                        filtered,
+                        unreachable,
+                        format_metrics(&pinger.metrics())
+                    );
                 }
                 indices_since_last_checkpoint += 1;
 
                 // Process result
-                if let Some(latency) = latency {
-                    out_data.push(encode_result(index, latency));
+                if let Some(reply) = reply {
+                    out_data.push(encode_result(index, reply.rtt));
                     if out_data.len() > 10 * parallelism_target {
                         out_file.write_all(&out_data.concat()).await.unwrap();
                         out_data.clear();
@@ -295,8 +373,16 @@ pub async fn scan(conf: Configuration) {
     out_file.write_all(&out_data.concat()).await.unwrap();
     out_data.clear();
 
+    // Stored after the results
+    let metrics = pinger.metrics();
+    println!("Metrics: {}", format_metrics(&metrics));
+    out_file
+        .write_all(&ScanMetrics::from(&metrics).encode())
+        .await
+        .unwrap();
+
     // Cleanup
-    pinger.stop().await;
+    pinger.shutdown().await;
     let _ = monitor_link_end.send(());
     let _ = monitor_cpu_end.send(());
 }
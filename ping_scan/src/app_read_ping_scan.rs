@@ -2,10 +2,12 @@ mod configuration;
 mod cursor;
 mod deprecated;
 mod internet;
+mod metrics;
 
 use configuration::{Configuration, EncodableConfiguration};
 use cursor::CursorExt;
 use internet::u32_to_ip;
+use metrics::{ScanMetrics, FOOTER_SIZE};
 use std::convert::TryInto;
 use std::time::Duration;
 use tokio::io::AsyncReadExt;
@@ -24,6 +26,33 @@ pub fn decode_result(mut data: &[u8]) -> (u32, Duration) {
     (index, Duration::from_nanos(latency as u64 * 10))
 }
 
+// Metrics ending the file, and their size with the footer
+async fn read_metrics(in_file: &mut tokio::fs::File, data_size: u64) -> Option<(ScanMetrics, u64)> {
+    if data_size < FOOTER_SIZE as u64 {
+        return None;
+    }
+    let mut footer = [0u8; FOOTER_SIZE];
+    in_file
+        .seek(std::io::SeekFrom::End(-(FOOTER_SIZE as i64)))
+        .await
+        .ok()?;
+    in_file.read_exact(&mut footer).await.ok()?;
+    let size = ScanMetrics::decode_footer(&footer)?;
+    let total_size = (size + FOOTER_SIZE) as u64;
+    if total_size > data_size {
+        return None;
+    }
+    let mut data = vec![0u8; size];
+    in_file
+        .seek(std::io::SeekFrom::End(-(total_size as i64)))
+        .await
+        .ok()?;
+    in_file.read_exact(&mut data).await.ok()?;
+    // Results that happen to end like a footer
+    let metrics = ScanMetrics::decode(&data).ok()?;
+    Some((metrics, total_size))
+}
+
 pub struct CursorCherryPick<Iter: Iterator<Item = u32>> {
     iter: Iter,
     index: u32,
@@ -111,6 +140,13 @@ async fn main() {
     let (conf, conf_size) = conf.unwrap();
     println!("Configuration: {:#?}", conf);
 
+    // Scans store the metrics of their pinger after the results
+    let data_size = in_file.metadata().await.unwrap().len() - conf_size as u64;
+    let (metrics, results_size) = match read_metrics(&mut in_file, data_size).await {
+        Some((metrics, size)) => (Some(metrics), data_size - size),
+        None => (None, data_size),
+    };
+
     in_file
         .seek(std::io::SeekFrom::Start(conf_size as u64))
         .await
@@ -122,9 +158,13 @@ async fn main() {
         .unwrap();
     let mut cursor_db = CursorCherryPick::new(cursor.to_iter());
     let mut buffer = [0u8; 8];
-    while in_file.read_exact(&mut buffer).await.is_ok() {
+    let mut results = (&mut in_file).take(results_size);
+    while results.read_exact(&mut buffer).await.is_ok() {
         let (index, latency) = decode_result(&buffer);
         let addr = u32_to_ip(cursor_db.get(index));
         println!("{:>width$} => {:?}", addr, latency, width = 15);
     }
+    if let Some(metrics) = metrics {
+        println!("Metrics: {:#?}", metrics);
+    }
 }
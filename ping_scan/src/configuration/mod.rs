@@ -8,7 +8,6 @@ pub mod v0;
 pub mod v1;
 
 // TODO Add start date
-// TODO Add source IP
 // TODO Do something JSON based instead
 // TODO Add first byte as version on u8 (for cross version compatible parser/converter)
 // TODO Add conf length on first u32 to be able to skip config, for simpler parsers
@@ -46,11 +45,25 @@ pub trait EncodableConfiguration {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitConfiguration {
+    pub pps: u32,
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PingConfiguration {
     pub timeout: Duration,
     pub size: u16,
     pub parallelism: u32,
     pub ttl: u8,
+    // Missing from the files written before it existed
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfiguration>,
+    // Address and interface the probes were bound to, for multi-homed scanners
+    #[serde(default)]
+    pub source: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub device: Option<String>,
 }
 impl PingConfiguration {
     pub fn from_args(args: &[String]) -> Result<Self, String> {
@@ -58,12 +71,45 @@ impl PingConfiguration {
         let size: u16 = field_from_args(args, "--ping_size")?;
         let parallelism: u32 = field_from_args(args, "--ping_parallelism")?;
         let ttl: u8 = field_from_args(args, "--ping_ttl")?;
+        // Optional
+        let rate_limit = if args.iter().any(|arg| arg == "--ping_rate") {
+            let pps = field_from_args(args, "--ping_rate")?;
+            if pps == 0 {
+                return Err("Bad ping rate: 0 packets per second".to_string());
+            }
+            let burst = if args.iter().any(|arg| arg == "--ping_burst") {
+                field_from_args(args, "--ping_burst")?
+            } else {
+                1
+            };
+            Some(RateLimitConfiguration { pps, burst })
+        } else {
+            None
+        };
+        let source = if args.iter().any(|arg| arg == "--ping_source") {
+            let source = field_from_args::<String>(args, "--ping_source")?;
+            Some(
+                source
+                    .parse()
+                    .map_err(|e| format!("Bad ping source: {:?}", e))?,
+            )
+        } else {
+            None
+        };
+        let device = if args.iter().any(|arg| arg == "--ping_device") {
+            Some(field_from_args(args, "--ping_device")?)
+        } else {
+            None
+        };
 
         Ok(Self {
             timeout: Duration::from_millis(timeout),
             size,
             parallelism,
             ttl,
+            rate_limit,
+            source,
+            device,
         })
     }
 }
@@ -239,7 +285,7 @@ impl EncodableConfiguration for Configuration {
     fn encode(&self) -> Box<[u8]> {
         let data = v1::Configuration {
             cursor: self.cursor,
-            ping: self.ping,
+            ping: self.ping.clone(),
             link_state_monitor: self.link_state_monitor,
             cpu_load_monitor: self.cpu_load_monitor,
             data_type: self.data_type,
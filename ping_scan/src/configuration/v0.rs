@@ -361,6 +361,9 @@ impl EncodableConfiguration for Configuration {
                 size: self.ping.size,
                 parallelism: self.ping.parallelism,
                 ttl: self.ping.ttl,
+                rate_limit: None,
+                source: None,
+                device: None,
             },
             link_state_monitor: None,
             cpu_load_monitor: None,
@@ -4,8 +4,6 @@ use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
 use std::time::Duration;
 
-// TODO Add source IP
-
 fn read_le_u32(input: &mut &[u8]) -> u32 {
     let (int_bytes, rest) = input.split_at(std::mem::size_of::<u32>());
     *input = rest;
@@ -42,7 +40,7 @@ impl EncodableConfiguration for Configuration {
     fn as_standard_configuration(&self) -> super::Configuration {
         super::Configuration {
             cursor: self.cursor,
-            ping: self.ping,
+            ping: self.ping.clone(),
             link_state_monitor: self.link_state_monitor.clone(),
             cpu_load_monitor: self.cpu_load_monitor,
             start_date: self.start_date,
@@ -56,7 +54,7 @@ impl EncodableConfiguration for Configuration {
 mod test {
     use super::super::{
         CpuLoadMonitorConfiguration, CursorConfiguration, CursorType, DataType,
-        LinkStateMonitorConfiguration, PingConfiguration,
+        LinkStateMonitorConfiguration, PingConfiguration, RateLimitConfiguration,
     };
     use super::*;
     use std::net::Ipv4Addr;
@@ -94,6 +92,9 @@ mod test {
                 size: 444,
                 parallelism: 90,
                 ttl: 34,
+                rate_limit: None,
+                source: None,
+                device: None,
             },
             data_type: DataType::PingLatency,
             start_date: std::time::SystemTime::now()
@@ -127,6 +128,12 @@ mod test {
                 size: 345,
                 parallelism: 4,
                 ttl: 22,
+                rate_limit: Some(RateLimitConfiguration {
+                    pps: 1000,
+                    burst: 10,
+                }),
+                source: Some(Ipv4Addr::new(192, 0, 2, 7)),
+                device: Some("eth1".to_string()),
             },
             data_type: DataType::PingLatency,
             start_date: 0,
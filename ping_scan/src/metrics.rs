@@ -0,0 +1,102 @@
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::time::Duration;
+use tokio_ip_ping_request::ping::PingerMetrics;
+
+// Ends the output files of the scans that stored their metrics
+const MAGIC: &[u8; 4] = b"PSMT";
+// Length of the metrics, then MAGIC
+#[allow(dead_code)]
+pub const FOOTER_SIZE: usize = 8;
+
+// Pinger metrics stored after the results of a scan
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScanMetrics {
+    pub sent: u64,
+    pub received: u64,
+    pub matched: u64,
+    pub errors: u64,
+    pub timeouts: u64,
+    pub send_failures: u64,
+    pub unmatched: u64,
+    pub late: u64,
+    // Absent from the metrics of older scans
+    #[serde(default)]
+    pub duplicates: u64,
+    // Upper bound and count of each RTT bucket. The last one has no bound.
+    pub rtt: Vec<(Option<Duration>, u64)>,
+    pub elapsed: Duration,
+}
+
+impl From<&PingerMetrics> for ScanMetrics {
+    fn from(metrics: &PingerMetrics) -> Self {
+        Self {
+            sent: metrics.sent,
+            received: metrics.received,
+            matched: metrics.matched,
+            errors: metrics.errors,
+            timeouts: metrics.timeouts,
+            send_failures: metrics.send_failures,
+            unmatched: metrics.unmatched,
+            late: metrics.late,
+            duplicates: metrics.duplicates,
+            rtt: metrics.rtt.buckets().collect(),
+            elapsed: metrics.elapsed,
+        }
+    }
+}
+
+impl ScanMetrics {
+    // JSON, its length as little endian u32, then MAGIC. Only ping_scan encodes the metrics,
+    // and only read_ping_scan decodes them.
+    #[allow(dead_code)]
+    pub fn encode(&self) -> Box<[u8]> {
+        let data = serde_json::to_vec(self).unwrap();
+        [&data[..], &(data.len() as u32).to_le_bytes(), MAGIC]
+            .concat()
+            .into()
+    }
+
+    // Size of the metrics before the last FOOTER_SIZE bytes of a file, if they end it
+    #[allow(dead_code)]
+    pub fn decode_footer(footer: &[u8]) -> Option<usize> {
+        if footer.len() != FOOTER_SIZE || &footer[4..] != MAGIC {
+            return None;
+        }
+        Some(u32::from_le_bytes(footer[..4].try_into().unwrap()) as usize)
+    }
+
+    #[allow(dead_code)]
+    pub fn decode(data: &[u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_consistency() {
+        let metrics = ScanMetrics {
+            sent: 10,
+            received: 9,
+            matched: 7,
+            errors: 1,
+            timeouts: 2,
+            send_failures: 0,
+            unmatched: 1,
+            late: 0,
+            duplicates: 1,
+            rtt: vec![(Some(Duration::from_millis(10)), 5), (None, 2)],
+            elapsed: Duration::from_secs(3),
+        };
+        let encoded = metrics.encode();
+        let (data, footer) = encoded.split_at(encoded.len() - FOOTER_SIZE);
+        assert_eq!(ScanMetrics::decode_footer(footer), Some(data.len()));
+        assert_eq!(ScanMetrics::decode(data).unwrap(), metrics);
+
+        // Results of a scan without metrics
+        assert_eq!(ScanMetrics::decode_footer(&[1, 0, 0, 0, 2, 0, 0, 0]), None);
+    }
+}
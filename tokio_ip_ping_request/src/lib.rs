@@ -1,3 +1,5 @@
 pub mod measure_route;
 pub mod ping;
+pub mod pmtu;
+pub mod stream;
 pub mod traceroute;
@@ -1,12 +1,12 @@
 use crate::ping::icmp;
-pub use crate::ping::PingError;
-use std::net::Ipv4Addr;
+pub use crate::ping::{PingError, PingReply};
+use std::net::IpAddr;
 use std::time::Duration;
 use tokio::sync::mpsc;
 
 #[derive(Debug)]
 pub struct RouteNode {
-    addr: Ipv4Addr,
+    addr: IpAddr,
     latency: Duration,
 }
 
@@ -24,14 +24,14 @@ pub enum RouteMeasureResult {
 
 #[derive(Debug)]
 pub enum RouteMeasureData {
-    Ping(Duration),
+    Ping(PingReply),
     TimeExceeded(RouteNode),
     Result(RouteMeasureResult),
 }
 
 async fn icmp_ttl_stats(
     pinger: &icmp::Pinger,
-    addr: Ipv4Addr,
+    addr: IpAddr,
     ttl: u8,
     timeout: Duration,
     flow_id: u16,
@@ -41,13 +41,13 @@ async fn icmp_ttl_stats(
     let mut ret = 0;
     for _ in 0..nb_retries {
         match pinger.ping(addr, ttl, timeout, flow_id).await {
-            Ok(latency) => {
-                if tx.send(Ok(RouteMeasureData::Ping(latency))).await.is_err() {
+            Ok(reply) => {
+                if tx.send(Ok(RouteMeasureData::Ping(reply))).await.is_err() {
                     return Err(());
                 }
                 ret += 1;
             }
-            Err(PingError::TimeExceeded { addr, latency }) => {
+            Err(PingError::TimeExceeded { addr, latency, .. }) => {
                 if tx
                     .send(Ok(RouteMeasureData::TimeExceeded(RouteNode {
                         addr,
@@ -59,10 +59,6 @@ async fn icmp_ttl_stats(
                     return Err(());
                 }
             }
-            Err(PingError::Timeout) => {
-                let _ = tx.send(Err(PingError::Timeout)).await;
-                return Err(());
-            }
             Err(error) => {
                 let _ = tx.send(Err(error)).await;
                 return Err(());
@@ -74,7 +70,7 @@ async fn icmp_ttl_stats(
 
 pub async fn icmp_measure_route_to_channel(
     pinger: icmp::Pinger,
-    addr: Ipv4Addr,
+    addr: IpAddr,
     max_ttl: u8,
     timeout: Duration,
     flow_id: u16,
@@ -83,8 +79,8 @@ pub async fn icmp_measure_route_to_channel(
 ) {
     // Check that the target is reachable
     match pinger.ping(addr, max_ttl, timeout, flow_id).await {
-        Ok(latency) => {
-            if tx.send(Ok(RouteMeasureData::Ping(latency))).await.is_err() {
+        Ok(reply) => {
+            if tx.send(Ok(RouteMeasureData::Ping(reply))).await.is_err() {
                 return;
             }
         }
@@ -99,14 +95,14 @@ pub async fn icmp_measure_route_to_channel(
     let mut distance = max_ttl - diff;
     while diff > 0 {
         match pinger.ping(addr, distance, timeout, flow_id).await {
-            Ok(latency) => {
-                if tx.send(Ok(RouteMeasureData::Ping(latency))).await.is_err() {
+            Ok(reply) => {
+                if tx.send(Ok(RouteMeasureData::Ping(reply))).await.is_err() {
                     return;
                 }
                 diff /= 2;
                 distance -= diff;
             }
-            Err(PingError::TimeExceeded { addr, latency }) => {
+            Err(PingError::TimeExceeded { addr, latency, .. }) => {
                 if tx
                     .send(Ok(RouteMeasureData::TimeExceeded(RouteNode {
                         addr,
@@ -193,7 +189,7 @@ pub async fn icmp_measure_route_to_channel(
 
 pub fn icmp_measure_route(
     pinger: icmp::Pinger,
-    addr: Ipv4Addr,
+    addr: IpAddr,
     max_ttl: u8,
     timeout: Duration,
     flow_id: u16,
@@ -211,3 +207,51 @@ pub fn icmp_measure_route(
     ));
     rx
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ping::icmp::PingerOptions;
+    use crate::ping::simulation::{Hop, SimulatedNetwork};
+    use crate::ping::tun;
+    use std::net::Ipv4Addr;
+
+    // Three routers before 198.18.2.100
+    fn network() -> SimulatedNetwork {
+        let latency = Duration::from_millis(1);
+        let routers = ["198.18.2.11", "198.18.2.12", "198.18.2.13"]
+            .iter()
+            .map(|addr| Hop::new(addr.parse().unwrap(), latency))
+            .collect();
+        SimulatedNetwork::new(1).route(
+            Hop::new("198.18.2.100".parse().unwrap(), latency),
+            vec![routers],
+        )
+    }
+
+    async fn check_route_length(pinger: icmp::Pinger) {
+        let host = "198.18.2.100".parse().unwrap();
+        let mut rx = icmp_measure_route(pinger.clone(), host, 16, Duration::from_secs(1), 0, 3);
+        let mut result = None;
+        while let Some(data) = rx.recv().await {
+            if let Ok(RouteMeasureData::Result(data)) = data {
+                result = Some(data);
+            }
+        }
+        assert!(matches!(result, Some(RouteMeasureResult::Stable(4))));
+        pinger.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn route_length() {
+        check_route_length(network().pinger(PingerOptions::default())).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs CAP_NET_ADMIN and CAP_NET_RAW"]
+    async fn route_length_through_tun() {
+        let (tun, pinger) = tun::test_pinger("pingtest2", Ipv4Addr::new(198, 18, 2, 1), network());
+        check_route_length(pinger).await;
+        drop(tun);
+    }
+}
@@ -1,49 +1,58 @@
 use pnet::packet::icmp;
 use pnet::packet::icmp::echo_request;
+use pnet::packet::icmpv6;
 use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::packet::Packet;
-use pnet::transport::icmp_packet_iter;
-use pnet::transport::transport_channel;
-use pnet::transport::TransportChannelType::Layer4;
-use pnet::transport::TransportProtocol::Ipv4;
-use pnet::transport::{TransportReceiver, TransportSender};
-use std::collections::BTreeMap;
-use std::net::IpAddr;
-use std::net::Ipv4Addr;
-use std::time::{Duration, Instant};
-use tokio::sync::mpsc;
+use pnet::packet::ipv4;
+use pnet::packet::{MutablePacket, Packet};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
 use tokio::sync::oneshot;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
 
+use super::socket::{self, Socket};
+use super::transport::{Incoming, Transport};
 use super::*;
 
 #[derive(Debug)]
 pub struct OngoingRequest {
-    start: Instant,
+    sent: SendTime,
     stop: Instant,
-    response_channel: oneshot::Sender<Result<Duration, PingError>>,
+    // Payload of the echo request, expected back
+    payload: Box<[u8]>,
+    response_channel: oneshot::Sender<Result<PingReply, PingError>>,
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct PingIdentifier {
-    responder: Ipv4Addr,
+    responder: IpAddr,
     id: u16,
     sn: u16,
     stop: Instant,
-    destination: Ipv4Addr,
+    // Kernel receive timestamp
+    received_at: Option<SystemTime>,
+    destination: IpAddr,
+    // Size of the ICMP payload
+    size: usize,
+    ip: IpHeaders,
 }
 
 #[derive(Debug, Clone)]
 pub enum PingRequestResponse {
-    PingResponse,
-    PingTimeExceeded,
+    PingResponse(Box<[u8]>),
+    PingTimeExceeded(Vec<MplsLabel>),
     IcmpError(Box<[u8]>),
 }
 
 impl PingRequestResponse {
     fn build_input(self, id: PingIdentifier) -> Input {
         match self {
-            Self::PingResponse => Input::PingResponse(id),
-            Self::PingTimeExceeded => Input::PingTimeExceeded(id),
+            Self::PingResponse(payload) => Input::PingResponse(id, payload),
+            Self::PingTimeExceeded(mpls) => Input::PingTimeExceeded(id, mpls),
             Self::IcmpError(v) => {
                 let p = icmp::IcmpPacket::new(&v).unwrap();
                 let ty = p.get_icmp_type();
@@ -62,288 +71,1635 @@ impl PingRequestResponse {
 #[derive(Debug)]
 pub enum Input {
     PingRequest(PingRequest),
-    PingResponse(PingIdentifier),
-    PingTimeExceeded(PingIdentifier),
+    PingResponse(PingIdentifier, Box<[u8]>),
+    PingTimeExceeded(PingIdentifier, Vec<MplsLabel>),
     IcmpError {
         id: PingIdentifier,
         ty: icmp::IcmpType,
         code: icmp::IcmpCode,
         data: Box<[u8]>,
     },
+    SetRateLimit(Option<RateLimit>),
     Timeout,
-    Stop,
+    // Signalled once the backend is done
+    Stop(oneshot::Sender<()>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketMode {
+    // Raw sockets, which need root or CAP_NET_RAW
+    Raw,
+    // ICMP datagram sockets, open to the groups in net.ipv4.ping_group_range. The kernel
+    // picks the echo identifier and only reports ICMP errors on the error queue.
+    Datagram,
+    // Raw sockets, or datagram sockets when raw ones are not permitted
+    Auto,
+}
+
+#[derive(Debug, Clone)]
+pub struct PingerOptions {
+    // Size in bytes of the ICMP packets to send
+    pub size: u16,
+    // Number of requests that can wait for the backend
+    pub parallelism: usize,
+    // Send and receive whole IPv4 packets, to report the IP header of the replies
+    pub ip_header: bool,
+    // Measure round trip times with kernel software timestamps when available
+    pub kernel_timestamps: bool,
+    // Not compatible with `ip_header` when resolving to datagram sockets
+    pub socket_mode: SocketMode,
+    // Repeated after the nonce and the timestamp of the payload. Zeros when empty.
+    pub pattern: Vec<u8>,
+    // Requests above the limit wait in the backend
+    pub rate_limit: Option<RateLimit>,
+    // Packets sent and received per system call, with sendmmsg and recvmmsg. Requests
+    // arriving together are sent together. Kernel transmit timestamps are not available
+    // above 1.
+    pub batch_size: usize,
+    // Source address of the IPv4 probes. Must belong to an interface of the host.
+    pub source: Option<Ipv4Addr>,
+    // Source address of the IPv6 probes, which then become mandatory
+    pub source6: Option<Ipv6Addr>,
+    // Send and receive through this interface only (SO_BINDTODEVICE)
+    pub device: Option<String>,
+    // How long answered and timed out requests are remembered, to report their duplicate
+    // and late replies instead of unmatched ones
+    pub grace_window: Duration,
+}
+
+impl Default for PingerOptions {
+    fn default() -> Self {
+        Self {
+            size: 64,
+            parallelism: 20,
+            ip_header: false,
+            kernel_timestamps: false,
+            socket_mode: SocketMode::Auto,
+            pattern: vec![],
+            rate_limit: None,
+            batch_size: 1,
+            source: None,
+            source6: None,
+            device: None,
+            grace_window: GRACE_WINDOW,
+        }
+    }
 }
 
 pub struct PingerBackend {
     // Size in bytes of the payload to send.  Default is 16 bytes
     size: usize,
+    // The IPv4 socket includes the IP header (Layer3)
+    ip_header: bool,
+    pattern: Vec<u8>,
+    rate_limit: Option<RateLimit>,
+    batch_size: usize,
+    grace_window: Duration,
+    counters: Arc<Counters>,
     // Request reception
     command_rx: mpsc::Receiver<Input>,
-    // Request sending
-    command_tx: mpsc::Sender<Input>,
+    // Fed by the listeners
+    reply_rx: mpsc::Receiver<Input>,
+    listeners: Vec<JoinHandle<()>>,
+    diagnostics: broadcast::Sender<Diagnostic>,
+    // Datagram sockets, matched on sequence numbers
+    datagram: bool,
     // IPv4
-    tx: TransportSender,
+    tx: Arc<dyn Transport>,
+    // IPv6. Missing when the host has no IPv6 support.
+    tx6: Option<Arc<dyn Transport>>,
+    // Kernel timestamps enabled on each socket
+    timestamping: socket::Timestamping,
+    timestamping6: socket::Timestamping,
+}
+
+// Messages about our probes. Any other ICMP message is none of our business, like the errors
+// about the TCP and UDP traffic of the host. Errors too short to tell are ours.
+fn expected_icmpv4(packet: &icmp::IcmpPacket) -> bool {
+    let ty = packet.get_icmp_type();
+    if ty == icmp::IcmpTypes::EchoReply {
+        return true;
+    }
+    if ![
+        icmp::IcmpTypes::DestinationUnreachable,
+        icmp::IcmpTypes::TimeExceeded,
+        icmp::IcmpTypes::ParameterProblem,
+    ]
+    .contains(&ty)
+    {
+        return false;
+    }
+    // The invoking packet follows 4 bytes of type specific data
+    match ipv4::Ipv4Packet::new(packet.payload().get(4..).unwrap_or_default()) {
+        Some(quoted) => {
+            let quoted_ty = quoted
+                .payload()
+                .first()
+                .copied()
+                .unwrap_or(icmp::IcmpTypes::EchoRequest.0);
+            quoted.get_next_level_protocol() == IpNextHeaderProtocols::Icmp
+                && quoted_ty == icmp::IcmpTypes::EchoRequest.0
+        }
+        None => true,
+    }
+}
+
+fn expected_icmpv6(packet: &icmpv6::Icmpv6Packet) -> bool {
+    let ty = packet.get_icmpv6_type();
+    if ty == icmpv6::Icmpv6Types::EchoReply {
+        return true;
+    }
+    if ![
+        icmpv6::Icmpv6Types::DestinationUnreachable,
+        icmpv6::Icmpv6Types::PacketTooBig,
+        icmpv6::Icmpv6Types::TimeExceeded,
+        icmpv6::Icmpv6Types::ParameterProblem,
+    ]
+    .contains(&ty)
+    {
+        return false;
+    }
+    match pnet::packet::ipv6::Ipv6Packet::new(packet.payload().get(4..).unwrap_or_default()) {
+        Some(quoted) => {
+            let quoted_ty = quoted
+                .payload()
+                .first()
+                .copied()
+                .unwrap_or(icmpv6::Icmpv6Types::EchoRequest.0);
+            quoted.get_next_header() == IpNextHeaderProtocols::Icmpv6
+                && quoted_ty == icmpv6::Icmpv6Types::EchoRequest.0
+        }
+        None => true,
+    }
+}
+
+// Request that was answered or timed out, kept a while to recognize its other replies
+struct Completed {
+    // When it was answered or timed out
+    stop: Instant,
+    sent: SendTime,
+    // Timed out and not answered since
+    timed_out: bool,
+    nonce: Box<[u8]>,
+}
+
+// Echo reply to a completed request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Straggler {
+    // First answer to a request that timed out at `stop`
+    Late { stop: Instant, sent: SendTime },
+    // Another copy of an answer
+    Duplicate { sent: SendTime },
+}
+
+// Recently completed requests
+struct CompletedRequests {
+    window: Duration,
+    // Sorted by completion time: timeouts complete in the past
+    order: BTreeSet<(Instant, RequestKey)>,
+    requests: BTreeMap<RequestKey, Completed>,
+}
+
+impl CompletedRequests {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            order: BTreeSet::new(),
+            requests: BTreeMap::new(),
+        }
+    }
+
+    fn push(
+        &mut self,
+        key: RequestKey,
+        stop: Instant,
+        sent: SendTime,
+        timed_out: bool,
+        payload: &[u8],
+    ) {
+        while let Some(&(oldest, key)) = self.order.iter().next() {
+            if self.order.len() < GRACE_CAPACITY
+                && stop.saturating_duration_since(oldest) <= self.window
+            {
+                break;
+            }
+            self.order.remove(&(oldest, key));
+            // The key may have been completed again since
+            if matches!(self.requests.get(&key), Some(v) if v.stop == oldest) {
+                self.requests.remove(&key);
+            }
+        }
+        let nonce = payload[..payload.len().min(NONCE_SIZE)].into();
+        self.order.insert((stop, key));
+        self.requests.insert(
+            key,
+            Completed {
+                stop,
+                sent,
+                timed_out,
+                nonce,
+            },
+        );
+    }
+
+    // Completed request `echoed` answers, when received at `now`. Later copies of a late
+    // reply are duplicates.
+    fn recognize(&mut self, key: &RequestKey, echoed: &[u8], now: Instant) -> Option<Straggler> {
+        let window = self.window;
+        let completed = self.requests.get_mut(key).filter(|completed| {
+            now.saturating_duration_since(completed.stop) <= window
+                && echoed.starts_with(&completed.nonce)
+        })?;
+        Some(if completed.timed_out {
+            completed.timed_out = false;
+            Straggler::Late {
+                stop: completed.stop,
+                sent: completed.sent,
+            }
+        } else {
+            Straggler::Duplicate {
+                sent: completed.sent,
+            }
+        })
+    }
+}
+
+// Echo request waiting for the rest of its batch
+struct Probe {
+    key: RequestKey,
+    addr: IpAddr,
+    packet: Vec<u8>,
+    options: socket::PacketOptions,
+    payload: Box<[u8]>,
+    request: PingRequest,
+}
+
+// Probes sent together, on the same socket and with the same DF setting
+struct Batch {
+    ipv6: bool,
+    // Of the socket
+    timestamping: socket::Timestamping,
+    probes: Vec<Probe>,
+}
+
+impl Batch {
+    fn new(size: usize) -> Self {
+        Self {
+            ipv6: false,
+            timestamping: socket::Timestamping::None,
+            probes: Vec::with_capacity(size),
+        }
+    }
+
+    fn flush(
+        &mut self,
+        socket: &dyn Transport,
+        ongoing: &mut BTreeMap<RequestKey, OngoingRequest>,
+        deadlines: &mut Deadlines<RequestKey>,
+        counters: &Counters,
+    ) {
+        let sent_at = SystemTime::now();
+        let mut packets = self
+            .probes
+            .iter()
+            .map(|p| (&p.packet[..], p.addr, p.options));
+        let sent = socket.send_batch(&mut packets);
+        counters.sent(sent);
+        counters.send_failure(self.probes.len() - sent);
+        // Every packet queues its transmit timestamp: drain them, and keep the last one for
+        // the whole batch
+        let kernel_sent_at = if self.timestamping == socket::Timestamping::SendAndReceive {
+            send_timestamp(socket, sent_at)
+        } else {
+            None
+        };
+        let start = Instant::now();
+        for (i, probe) in self.probes.drain(..).enumerate() {
+            if i >= sent {
+                let _ = probe
+                    .request
+                    .response_channel
+                    .send(Err(PingError::FailedToSendPacket));
+                continue;
+            }
+            let stop = start + probe.request.timeout;
+            deadlines.push(stop, probe.key);
+            ongoing.insert(
+                probe.key,
+                OngoingRequest {
+                    sent: SendTime {
+                        start,
+                        sent_at,
+                        kernel_sent_at,
+                    },
+                    stop,
+                    payload: probe.payload,
+                    response_channel: probe.request.response_channel,
+                },
+            );
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SendTime {
+    start: Instant,
+    sent_at: SystemTime,
+    // Kernel transmit timestamp
+    kernel_sent_at: Option<SystemTime>,
+}
+
+struct Timing {
+    sent_at: SystemTime,
+    received_at: SystemTime,
+    rtt: Duration,
+    clock: ClockSource,
+}
+
+// Prefer kernel timestamps, which do not include the scheduling of the listener thread and
+// of the backend.
+fn timing(sent: &SendTime, id: &PingIdentifier) -> Timing {
+    if let Some(received_at) = id.received_at {
+        let (sent_at, clock) = match sent.kernel_sent_at {
+            Some(sent_at) => (sent_at, ClockSource::Kernel),
+            None => (sent.sent_at, ClockSource::KernelReceive),
+        };
+        // The wall clock may have been stepped in between
+        if let Ok(rtt) = received_at.duration_since(sent_at) {
+            return Timing {
+                sent_at,
+                received_at,
+                rtt,
+                clock,
+            };
+        }
+    }
+    let rtt = if id.stop > sent.start {
+        id.stop.duration_since(sent.start)
+    } else {
+        Duration::from_nanos(10)
+    };
+    Timing {
+        sent_at: sent.sent_at,
+        received_at: sent.sent_at + rtt,
+        rtt,
+        clock: ClockSource::UserSpace,
+    }
+}
+
+// Transmit timestamp of the packet just sent. Older ones are left over from packets whose
+// timestamp came too late.
+fn send_timestamp(tx: &dyn Transport, before: SystemTime) -> Option<SystemTime> {
+    tx.last_send_timestamp()
+        .filter(|sent_at| *sent_at >= before)
+}
+
+pub const DIAGNOSTICS_CAPACITY: usize = 1024;
+// How long, and how many, completed requests are remembered to report their late and
+// duplicate replies
+pub const GRACE_WINDOW: Duration = Duration::from_secs(60);
+const GRACE_CAPACITY: usize = 1 << 16;
+const NONCE_SIZE: usize = 8;
+const TIMESTAMP_SIZE: usize = 8;
+
+// Echo request payload: nonce, send time in nanoseconds since the epoch, then the pattern.
+// Small payloads only keep the beginning.
+fn build_payload(size: usize, nonce: u64, sent_at: SystemTime, pattern: &[u8]) -> Vec<u8> {
+    let timestamp = sent_at
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let mut payload = Vec::with_capacity(NONCE_SIZE + TIMESTAMP_SIZE + size);
+    payload.extend_from_slice(&nonce.to_be_bytes());
+    payload.extend_from_slice(&timestamp.to_be_bytes());
+    if pattern.is_empty() {
+        payload.resize(size.max(payload.len()), 0);
+    } else {
+        payload.extend(
+            pattern
+                .iter()
+                .cycle()
+                .take(size.saturating_sub(payload.len())),
+        );
+    }
+    payload.truncate(size);
+    payload
+}
+
+// Set the first 2 bytes of the payload of an echo request so that its checksum comes out as
+// `checksum`, like UDP probes do
+fn tune_checksum(message: &mut [u8], checksum: u16) {
+    let offset = echo_request::EchoRequestPacket::minimum_packet_size();
+    message[offset..offset + 2].copy_from_slice(&[0, 0]);
+    let base = pnet::util::checksum(message, 1);
+    // Adding w to the one's complement sum turns the checksum c into c - w
+    let adjust = ones_complement_add(base, !checksum);
+    message[offset..offset + 2].copy_from_slice(&adjust.to_be_bytes());
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum EchoCheck {
+    Valid,
+    // The nonce is not ours: a late reply to an older request, or a forged one
+    Stale,
+    Invalid(EchoError),
+}
+
+fn check_echo(sent: &[u8], echoed: &[u8]) -> EchoCheck {
+    let nonce_len = NONCE_SIZE.min(sent.len()).min(echoed.len());
+    if sent[..nonce_len] != echoed[..nonce_len] {
+        return EchoCheck::Stale;
+    }
+    if let Some(offset) = sent.iter().zip(echoed).position(|(a, b)| a != b) {
+        return EchoCheck::Invalid(EchoError::Corrupted { offset });
+    }
+    if echoed.len() < sent.len() {
+        EchoCheck::Invalid(EchoError::Truncated {
+            sent: sent.len(),
+            echoed: echoed.len(),
+        })
+    } else if echoed.len() > sent.len() {
+        EchoCheck::Invalid(EchoError::Corrupted { offset: sent.len() })
+    } else {
+        EchoCheck::Valid
+    }
+}
+
+type RequestKey = (IpAddr, u16, u16);
+
+// Key of the ongoing requests. Datagram sockets get their echo identifier from the kernel, so
+// their probes are told apart by sequence number alone.
+fn request_key(datagram: bool, addr: IpAddr, id: u16, sn: u16) -> RequestKey {
+    if datagram {
+        (addr, 0, sn)
+    } else {
+        (addr, id, sn)
+    }
+}
+
+// Hands out identifier and sequence number pairs no ongoing request uses. Raw sockets count
+// on 32 bits: the sequence number, then the offset of the identifier from the flow id.
+struct IdAllocator {
+    counter: u32,
+}
+
+impl IdAllocator {
+    fn allocate<V>(
+        &mut self,
+        datagram: bool,
+        addr: IpAddr,
+        flow_id: u16,
+        ongoing: &BTreeMap<RequestKey, V>,
+    ) -> Option<(u16, u16)> {
+        // Every sequence number is tried once
+        for _ in 0..=0xFF_FF {
+            let counter = self.counter;
+            self.counter = self.counter.wrapping_add(1);
+            let id = flow_id.wrapping_add((counter >> 16) as u16);
+            let sn = counter as u16;
+            if !ongoing.contains_key(&request_key(datagram, addr, id, sn)) {
+                return Some((id, sn));
+            }
+        }
+        None
+    }
+}
+
+// Parse an ICMPv4 message coming from `addr`. `reply` is the header of the IP packet that
+// carried it, when the socket gives access to it.
+fn parse_icmpv4(
+    packet: &icmp::IcmpPacket,
+    addr: IpAddr,
+    reply: Option<Ipv4Header>,
+) -> Option<(PingRequestResponse, PingIdentifier)> {
+    if packet.get_icmp_type() == icmp::IcmpTypes::EchoReply {
+        let packet = icmp::echo_reply::EchoReplyPacket::new(packet.packet())?;
+        return Some((
+            PingRequestResponse::PingResponse(packet.payload().into()),
+            PingIdentifier {
+                responder: addr,
+                destination: addr,
+                id: packet.get_identifier(),
+                sn: packet.get_sequence_number(),
+                stop: Instant::now(),
+                received_at: None,
+                size: packet.payload().len(),
+                ip: IpHeaders {
+                    reply,
+                    quoted: None,
+                },
+            },
+        ));
+    }
+    let packet = icmp::time_exceeded::TimeExceededPacket::new(packet.packet())?;
+    let ipv4_packet = ipv4::Ipv4Packet::new(packet.payload())?;
+    // Errors about the TCP and UDP traffic of the host
+    if ipv4_packet.get_next_level_protocol() != IpNextHeaderProtocols::Icmp {
+        return None;
+    }
+    let icmp_packet = echo_request::EchoRequestPacket::new(ipv4_packet.payload())?;
+    if icmp_packet.get_icmp_type() != icmp::IcmpTypes::EchoRequest {
+        return None;
+    }
+
+    let id = PingIdentifier {
+        responder: addr,
+        id: icmp_packet.get_identifier(),
+        sn: icmp_packet.get_sequence_number(),
+        destination: IpAddr::V4(ipv4_packet.get_destination()),
+        stop: Instant::now(),
+        received_at: None,
+        size: icmp_packet.payload().len(),
+        ip: IpHeaders {
+            reply,
+            quoted: Some(Ipv4Header::from_packet(&ipv4_packet)),
+        },
+    };
+
+    if packet.get_icmp_type() == icmp::IcmpTypes::TimeExceeded {
+        let mpls = mpls_labels(false, packet.packet());
+        Some((PingRequestResponse::PingTimeExceeded(mpls), id))
+    } else {
+        Some((PingRequestResponse::IcmpError(packet.packet().into()), id))
+    }
+}
+
+// Parse an ICMPv6 message coming from `addr`
+fn parse_icmpv6(
+    packet: &icmpv6::Icmpv6Packet,
+    addr: IpAddr,
+) -> Option<(PingRequestResponse, PingIdentifier)> {
+    let ty = packet.get_icmpv6_type();
+    if ty == icmpv6::Icmpv6Types::EchoReply {
+        // Echo messages share their layout with ICMPv4
+        let packet = icmp::echo_reply::EchoReplyPacket::new(packet.packet())?;
+        return Some((
+            PingRequestResponse::PingResponse(packet.payload().into()),
+            PingIdentifier {
+                responder: addr,
+                destination: addr,
+                id: packet.get_identifier(),
+                sn: packet.get_sequence_number(),
+                stop: Instant::now(),
+                received_at: None,
+                size: packet.payload().len(),
+                ip: IpHeaders::default(),
+            },
+        ));
+    }
+    if ty != icmpv6::Icmpv6Types::TimeExceeded
+        && ty != icmpv6::Icmpv6Types::DestinationUnreachable
+        && ty != icmpv6::Icmpv6Types::PacketTooBig
+        && ty != icmpv6::Icmpv6Types::ParameterProblem
+    {
+        // Neighbour discovery and other unrelated traffic
+        return None;
+    }
+    // So do error messages: 4 bytes of header, 4 bytes of type specific data, then the
+    // invoking packet.
+    let packet = icmp::time_exceeded::TimeExceededPacket::new(packet.packet())?;
+    let ipv6_packet = pnet::packet::ipv6::Ipv6Packet::new(packet.payload())?;
+    if ipv6_packet.get_next_header() != IpNextHeaderProtocols::Icmpv6 {
+        return None;
+    }
+    let icmp_packet = echo_request::EchoRequestPacket::new(ipv6_packet.payload())?;
+    if icmp_packet.get_icmp_type().0 != icmpv6::Icmpv6Types::EchoRequest.0 {
+        return None;
+    }
+
+    let id = PingIdentifier {
+        responder: addr,
+        id: icmp_packet.get_identifier(),
+        sn: icmp_packet.get_sequence_number(),
+        destination: IpAddr::V6(ipv6_packet.get_destination()),
+        stop: Instant::now(),
+        received_at: None,
+        size: icmp_packet.payload().len(),
+        ip: IpHeaders::default(),
+    };
+
+    if ty == icmpv6::Icmpv6Types::TimeExceeded {
+        let mpls = mpls_labels(true, packet.packet());
+        Some((PingRequestResponse::PingTimeExceeded(mpls), id))
+    } else {
+        Some((PingRequestResponse::IcmpError(packet.packet().into()), id))
+    }
+}
+
+// ICMP error reported on the error queue of a datagram socket. The kernel gives back the
+// probe's ICMP message, without its IP header.
+fn parse_error_report(report: &socket::ErrorReport, probe: &[u8]) -> Option<Input> {
+    let icmp_packet = echo_request::EchoRequestPacket::new(probe)?;
+    let id = PingIdentifier {
+        responder: report.offender,
+        id: icmp_packet.get_identifier(),
+        sn: icmp_packet.get_sequence_number(),
+        destination: report.destination,
+        stop: Instant::now(),
+        received_at: report.timestamp,
+        size: icmp_packet.payload().len(),
+        ip: IpHeaders::default(),
+    };
+    let time_exceeded = if report.icmpv6 {
+        report.ty == icmpv6::Icmpv6Types::TimeExceeded.0
+    } else {
+        report.ty == icmp::IcmpTypes::TimeExceeded.0
+    };
+    if time_exceeded {
+        // The kernel does not pass on ICMP extensions
+        return Some(Input::PingTimeExceeded(id, vec![]));
+    }
+    // Rebuild the error message around the probe
+    let mut data = vec![report.ty, report.code, 0, 0];
+    data.extend_from_slice(&report.info.to_be_bytes());
+    data.extend_from_slice(probe);
+    Some(Input::IcmpError {
+        id,
+        ty: icmp::IcmpType(report.ty),
+        code: icmp::IcmpCode(report.code),
+        data: data.into(),
+    })
+}
+
+// Input for an IPv4 packet coming from `addr`. Packets about our probes that cannot be
+// parsed are reported.
+fn ipv4_input(
+    addr: IpAddr,
+    received_at: Option<SystemTime>,
+    buf: &[u8],
+    ip_header: bool,
+    diagnostics: &broadcast::Sender<Diagnostic>,
+) -> Option<Input> {
+    let packet = match ipv4::Ipv4Packet::new(buf) {
+        Some(packet) => packet,
+        None => {
+            let _ = diagnostics.send(unparseable(addr, buf));
+            return None;
+        }
+    };
+    let reply = if ip_header {
+        Some(Ipv4Header::from_packet(&packet))
+    } else {
+        None
+    };
+    let icmp_packet = match icmp::IcmpPacket::new(packet.payload()) {
+        Some(icmp_packet) => icmp_packet,
+        None => {
+            let _ = diagnostics.send(unparseable(addr, packet.payload()));
+            return None;
+        }
+    };
+    match parse_icmpv4(&icmp_packet, addr, reply) {
+        Some((command, mut id)) => {
+            id.received_at = received_at;
+            Some(command.build_input(id))
+        }
+        None => {
+            if expected_icmpv4(&icmp_packet) {
+                let _ = diagnostics.send(unparseable(addr, packet.payload()));
+            }
+            None
+        }
+    }
+}
+
+// Input for an ICMPv6 message coming from `addr`
+fn ipv6_input(
+    addr: IpAddr,
+    received_at: Option<SystemTime>,
+    buf: &[u8],
+    diagnostics: &broadcast::Sender<Diagnostic>,
+) -> Option<Input> {
+    let packet = match icmpv6::Icmpv6Packet::new(buf) {
+        Some(packet) => packet,
+        None => {
+            let _ = diagnostics.send(unparseable(addr, buf));
+            return None;
+        }
+    };
+    match parse_icmpv6(&packet, addr) {
+        Some((command, mut id)) => {
+            id.received_at = received_at;
+            Some(command.build_input(id))
+        }
+        None => {
+            if expected_icmpv6(&packet) {
+                let _ = diagnostics.send(unparseable(addr, buf));
+            }
+            None
+        }
+    }
+}
+
+// Wrap an ICMP message for a Layer3 socket. The kernel fills in the source address, the
+// identification and the checksum.
+fn build_ipv4_packet(destination: Ipv4Addr, options: &ProbeOptions, payload: &[u8]) -> Vec<u8> {
+    let header_size = ipv4::Ipv4Packet::minimum_packet_size();
+    let mut vec: Vec<u8> = vec![0; header_size + payload.len()];
+    let len = vec.len() as u16;
+    let mut ip_packet = ipv4::MutableIpv4Packet::new(&mut vec[..]).unwrap();
+    ip_packet.set_version(4);
+    ip_packet.set_header_length((header_size / 4) as u8);
+    ip_packet.set_total_length(len);
+    ip_packet.set_ttl(options.ttl);
+    ip_packet.set_dscp(options.dscp);
+    ip_packet.set_ecn(options.ecn);
+    if options.dont_fragment == Some(true) {
+        ip_packet.set_flags(ipv4::Ipv4Flags::DontFragment);
+    }
+    ip_packet.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+    ip_packet.set_destination(destination);
+    ip_packet.set_payload(payload);
+    vec
+}
+
+fn bind_socket(
+    socket: &Socket,
+    source: Option<IpAddr>,
+    device: Option<&str>,
+) -> Result<(), std::io::Error> {
+    if let Some(device) = device {
+        socket.bind_to_device(device)?;
+    }
+    if let Some(source) = source {
+        socket.bind(source)?;
+    }
+    Ok(())
+}
+
+// Open the IPv4 socket, and tell which kind it is
+fn open_ipv4_socket(options: &PingerOptions) -> Result<(Socket, bool), std::io::Error> {
+    let raw = || {
+        let socket = Socket::new(libc::AF_INET, libc::SOCK_RAW, libc::IPPROTO_ICMP)?;
+        if options.ip_header {
+            socket.set_header_included()?;
+        }
+        Ok((socket, false))
+    };
+    let datagram = || {
+        if options.ip_header {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Datagram sockets do not give access to the IP header",
+            ));
+        }
+        let socket = Socket::new(libc::AF_INET, libc::SOCK_DGRAM, libc::IPPROTO_ICMP)?;
+        Ok((socket, true))
+    };
+    match options.socket_mode {
+        SocketMode::Raw => raw(),
+        SocketMode::Datagram => datagram(),
+        SocketMode::Auto => match raw() {
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied && !options.ip_header => {
+                datagram()
+            }
+            res => res,
+        },
+    }
 }
 
 impl PingerBackend {
     pub fn start(
-        size: u16,
+        options: PingerOptions,
         command_rx: mpsc::Receiver<Input>,
-        command_tx: mpsc::Sender<Input>,
-    ) -> Result<(), std::io::Error> {
-        // TODO We need Layer3 to have access to the IP header.
-        let protocol = Layer4(Ipv4(IpNextHeaderProtocols::Icmp));
-        let (tx, rx) = match transport_channel(4096, protocol) {
-            Ok((tx, rx)) => (tx, rx),
-            Err(e) => return Err(e),
+        counters: Arc<Counters>,
+        diagnostics: broadcast::Sender<Diagnostic>,
+    ) -> Result<SocketMode, std::io::Error> {
+        let batch_size = options.batch_size.max(1);
+        // Replies have their own channel, so that they never wait behind requests
+        let (reply_tx, reply_rx) = mpsc::channel(options.parallelism.max(1));
+        let (socket, datagram) = open_ipv4_socket(&options)?;
+        let device = options.device.as_deref();
+        bind_socket(&socket, options.source.map(IpAddr::V4), device)?;
+        let socket6 = if datagram {
+            Socket::new(libc::AF_INET6, libc::SOCK_DGRAM, libc::IPPROTO_ICMPV6)
+        } else {
+            Socket::new(libc::AF_INET6, libc::SOCK_RAW, libc::IPPROTO_ICMPV6)
         };
 
-        let listener_tx = command_tx.clone();
-        std::thread::spawn(move || Self::run_ip_listener(rx, listener_tx));
+        // Replies wait in the socket while the backend is busy sending
+        let _ = socket.set_receive_buffer(RECEIVE_BUFFER_SIZE);
+        let tx = Arc::new(socket);
+        // Transmit timestamps share the error queue with the ICMP errors of datagram sockets
+        let timestamping = if options.kernel_timestamps {
+            tx.enable_timestamps(!datagram)
+        } else {
+            socket::Timestamping::None
+        };
+        let mut listeners = vec![];
+        if datagram {
+            tx.enable_errors(false)?;
+            let rx = AsyncFd::with_interest(tx.clone(), Interest::READABLE | Interest::ERROR)?;
+            listeners.push(tokio::spawn(Self::run_datagram_listener(
+                rx,
+                batch_size,
+                counters.clone(),
+                reply_tx.clone(),
+                diagnostics.clone(),
+            )));
+        } else {
+            let rx = AsyncFd::with_interest(tx.clone(), Interest::READABLE)?;
+            listeners.push(tokio::spawn(Self::run_ip_listener(
+                rx,
+                options.ip_header,
+                batch_size,
+                counters.clone(),
+                reply_tx.clone(),
+                diagnostics.clone(),
+            )));
+        }
+
+        // IPv6 is optional
+        let socket6 = socket6.and_then(|socket6| {
+            bind_socket(&socket6, options.source6.map(IpAddr::V6), device)?;
+            let _ = socket6.set_receive_buffer(RECEIVE_BUFFER_SIZE);
+            let tx6 = Arc::new(socket6);
+            let rx6 = if datagram {
+                tx6.enable_errors(true)?;
+                AsyncFd::with_interest(tx6.clone(), Interest::READABLE | Interest::ERROR)?
+            } else {
+                AsyncFd::with_interest(tx6.clone(), Interest::READABLE)?
+            };
+            Ok((tx6, rx6))
+        });
+        let socket6 = match socket6 {
+            Err(e) if options.source6.is_some() => return Err(e),
+            socket6 => socket6,
+        };
+        let (tx6, timestamping6) = match socket6 {
+            Ok((tx6, rx6)) => {
+                let timestamping6 = if options.kernel_timestamps {
+                    tx6.enable_timestamps(!datagram)
+                } else {
+                    socket::Timestamping::None
+                };
+                let counters = counters.clone();
+                let diagnostics = diagnostics.clone();
+                listeners.push(if datagram {
+                    tokio::spawn(Self::run_datagram_listener(
+                        rx6,
+                        batch_size,
+                        counters,
+                        reply_tx,
+                        diagnostics,
+                    ))
+                } else {
+                    tokio::spawn(Self::run_ipv6_listener(
+                        rx6,
+                        batch_size,
+                        counters,
+                        reply_tx,
+                        diagnostics,
+                    ))
+                });
+                (Some(tx6 as Arc<dyn Transport>), timestamping6)
+            }
+            Err(_) => (None, socket::Timestamping::None),
+        };
 
         let backend = Self {
-            size: size as usize,
+            size: options.size as usize,
+            ip_header: options.ip_header,
+            pattern: options.pattern,
+            rate_limit: options.rate_limit,
+            batch_size,
+            grace_window: options.grace_window,
+            counters,
             command_rx,
-            command_tx,
+            reply_rx,
+            listeners,
+            diagnostics,
+            datagram,
             tx,
+            tx6,
+            timestamping,
+            timestamping6,
         };
         tokio::spawn(backend.run());
-        Ok(())
+        Ok(if datagram {
+            SocketMode::Datagram
+        } else {
+            SocketMode::Raw
+        })
     }
 
-    pub fn run_ip_listener(mut rx: TransportReceiver, command_tx: mpsc::Sender<Input>) {
-        let mut iter = icmp_packet_iter(&mut rx);
+    // Send the probes through `transport`, for IPv4 and IPv6 alike, instead of sockets
+    pub fn start_with_transport(
+        options: PingerOptions,
+        transport: Arc<dyn Transport>,
+        incoming: mpsc::Receiver<Incoming>,
+        command_rx: mpsc::Receiver<Input>,
+        counters: Arc<Counters>,
+        diagnostics: broadcast::Sender<Diagnostic>,
+    ) {
+        let (reply_tx, reply_rx) = mpsc::channel(options.parallelism.max(1));
+        let listener = tokio::spawn(Self::run_transport_listener(
+            incoming,
+            options.ip_header,
+            counters.clone(),
+            reply_tx,
+            diagnostics.clone(),
+        ));
+        let backend = Self {
+            size: options.size as usize,
+            ip_header: options.ip_header,
+            pattern: options.pattern,
+            rate_limit: options.rate_limit,
+            batch_size: options.batch_size.max(1),
+            grace_window: options.grace_window,
+            counters,
+            command_rx,
+            reply_rx,
+            listeners: vec![listener],
+            diagnostics,
+            datagram: false,
+            tx: transport.clone(),
+            tx6: Some(transport),
+            timestamping: socket::Timestamping::None,
+            timestamping6: socket::Timestamping::None,
+        };
+        tokio::spawn(backend.run());
+    }
+
+    // Packets from a transport, parsed like those of raw sockets
+    pub async fn run_transport_listener(
+        mut incoming: mpsc::Receiver<Incoming>,
+        ip_header: bool,
+        counters: Arc<Counters>,
+        reply_tx: mpsc::Sender<Input>,
+        diagnostics: broadcast::Sender<Diagnostic>,
+    ) {
         loop {
-            match iter.next() {
-                Ok((packet, addr)) => {
-                    let addr = match addr {
-                        IpAddr::V4(addr) => addr,
-                        _ => continue,
-                    };
-                    let command;
-                    if packet.get_icmp_type() == icmp::IcmpTypes::EchoReply {
-                        let packet =
-                            icmp::echo_reply::EchoReplyPacket::new(packet.packet()).unwrap();
-                        command = Some((
-                            PingRequestResponse::PingResponse,
-                            PingIdentifier {
-                                responder: addr,
-                                destination: addr,
-                                id: packet.get_identifier(),
-                                sn: packet.get_sequence_number(),
-                                stop: Instant::now(),
-                            },
-                        ));
-                    } else {
-                        let packet = if let Some(packet) =
-                            icmp::time_exceeded::TimeExceededPacket::new(packet.packet())
-                        {
-                            packet
-                        } else {
-                            continue;
-                        };
-                        let ipv4_packet = if let Some(packet) =
-                            pnet::packet::ipv4::Ipv4Packet::new(packet.payload())
-                        {
-                            packet
-                        } else {
-                            continue;
-                        };
-                        let icmp_packet = if let Some(packet) =
-                            echo_request::EchoRequestPacket::new(ipv4_packet.payload())
-                        {
-                            packet
-                        } else {
-                            continue;
-                        };
+            let packet = tokio::select! {
+                packet = incoming.recv() => packet,
+                _ = reply_tx.closed() => return,
+            };
+            let packet = match packet {
+                Some(packet) => packet,
+                None => return,
+            };
+            counters.received(1);
+            let input = match packet.source {
+                IpAddr::V4(_) => ipv4_input(
+                    packet.source,
+                    packet.timestamp,
+                    &packet.data,
+                    ip_header,
+                    &diagnostics,
+                ),
+                IpAddr::V6(_) => {
+                    ipv6_input(packet.source, packet.timestamp, &packet.data, &diagnostics)
+                }
+            };
+            if let Some(input) = input {
+                if reply_tx.send(input).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
 
-                        let id = PingIdentifier {
-                            responder: addr,
-                            id: icmp_packet.get_identifier(),
-                            sn: icmp_packet.get_sequence_number(),
-                            destination: ipv4_packet.get_destination(),
-                            stop: Instant::now(),
-                        };
+    // Raw IPv4 sockets deliver the IP header along with the ICMP message
+    pub async fn run_ip_listener(
+        rx: AsyncFd<Arc<Socket>>,
+        ip_header: bool,
+        batch_size: usize,
+        counters: Arc<Counters>,
+        reply_tx: mpsc::Sender<Input>,
+        diagnostics: broadcast::Sender<Diagnostic>,
+    ) {
+        let mut batch = socket::RecvBatch::new(batch_size, MAX_PACKET_SIZE);
+        let mut inputs = Vec::with_capacity(batch_size);
+        loop {
+            let count = tokio::select! {
+                count = receive_batch(&rx, &mut batch, &diagnostics) => count,
+                _ = reply_tx.closed() => return,
+            };
+            let count = match count {
+                Some(count) => count,
+                None => return,
+            };
+            counters.received(count);
+            for (received, buf) in (0..count).filter_map(|i| batch.get(i)) {
+                if !received.addr.is_ipv4() {
+                    continue;
+                }
+                let input = ipv4_input(
+                    received.addr,
+                    received.timestamp,
+                    buf,
+                    ip_header,
+                    &diagnostics,
+                );
+                inputs.extend(input);
+            }
+            // The batch is only read before sending, as sending may wait
+            for input in inputs.drain(..) {
+                if reply_tx.send(input).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
 
-                        if packet.get_icmp_type() == icmp::IcmpTypes::TimeExceeded {
-                            command = Some((PingRequestResponse::PingTimeExceeded, id));
-                        } else {
-                            command =
-                                Some((PingRequestResponse::IcmpError(packet.packet().into()), id));
-                        }
+    pub async fn run_ipv6_listener(
+        rx: AsyncFd<Arc<Socket>>,
+        batch_size: usize,
+        counters: Arc<Counters>,
+        reply_tx: mpsc::Sender<Input>,
+        diagnostics: broadcast::Sender<Diagnostic>,
+    ) {
+        let mut batch = socket::RecvBatch::new(batch_size, MAX_PACKET_SIZE);
+        let mut inputs = Vec::with_capacity(batch_size);
+        loop {
+            let count = tokio::select! {
+                count = receive_batch(&rx, &mut batch, &diagnostics) => count,
+                _ = reply_tx.closed() => return,
+            };
+            let count = match count {
+                Some(count) => count,
+                None => return,
+            };
+            counters.received(count);
+            for (received, buf) in (0..count).filter_map(|i| batch.get(i)) {
+                if !received.addr.is_ipv6() {
+                    continue;
+                }
+                inputs.extend(ipv6_input(
+                    received.addr,
+                    received.timestamp,
+                    buf,
+                    &diagnostics,
+                ));
+            }
+            // The batch is only read before sending, as sending may wait
+            for input in inputs.drain(..) {
+                if reply_tx.send(input).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    // Datagram sockets only deliver echo replies, without IP header. ICMP errors come on the
+    // error queue.
+    pub async fn run_datagram_listener(
+        rx: AsyncFd<Arc<Socket>>,
+        batch_size: usize,
+        counters: Arc<Counters>,
+        reply_tx: mpsc::Sender<Input>,
+        diagnostics: broadcast::Sender<Diagnostic>,
+    ) {
+        let mut buf = [0u8; 4096];
+        let mut batch = socket::RecvBatch::new(batch_size, MAX_PACKET_SIZE);
+        let mut inputs = Vec::with_capacity(batch_size);
+        loop {
+            let event = tokio::select! {
+                count = receive_batch(&rx, &mut batch, &diagnostics) => Some(count),
+                guard = rx.ready(Interest::ERROR) => {
+                    if let Ok(mut guard) = guard {
+                        guard.clear_ready();
                     }
-                    if let Some((command, id)) = command {
-                        loop {
-                            match command_tx.try_send(command.clone().build_input(id)) {
-                                Ok(_) => break,
-                                Err(mpsc::error::TrySendError::Full(_)) => {
-                                    std::thread::sleep(Duration::from_millis(100))
-                                }
-                                Err(mpsc::error::TrySendError::Closed(_)) => return,
+                    None
+                }
+                _ = reply_tx.closed() => return,
+            };
+            let count = match event {
+                Some(Some(count)) => count,
+                Some(None) => return,
+                None => {
+                    loop {
+                        let report = match rx.get_ref().recv_error(&mut buf) {
+                            Ok(report) => report,
+                            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                            // Not about an ICMP message
+                            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => continue,
+                            Err(e) => {
+                                let _ = diagnostics.send(socket_error(&e));
+                                break;
+                            }
+                        };
+                        counters.received(1);
+                        let probe = &buf[..report.len.min(buf.len())];
+                        if let Some(input) = parse_error_report(&report, probe) {
+                            if reply_tx.send(input).await.is_err() {
+                                return;
                             }
                         }
                     }
+                    continue;
                 }
-                Err(e) => {
-                    // TODO Return proper errors somehow
-                    eprintln!("An error occurred while reading: {}", e);
+            };
+            counters.received(count);
+            for (received, buf) in (0..count).filter_map(|i| batch.get(i)) {
+                // Only echo replies come this way
+                let command = match received.addr {
+                    IpAddr::V4(_) => icmp::IcmpPacket::new(buf)
+                        .and_then(|packet| parse_icmpv4(&packet, received.addr, None)),
+                    IpAddr::V6(_) => icmpv6::Icmpv6Packet::new(buf)
+                        .and_then(|packet| parse_icmpv6(&packet, received.addr)),
+                };
+                match command {
+                    Some((command, mut id)) => {
+                        id.received_at = received.timestamp;
+                        inputs.push(command.build_input(id));
+                    }
+                    None => {
+                        let _ = diagnostics.send(unparseable(received.addr, buf));
+                    }
+                }
+            }
+            // The batch is only read before sending, as sending may wait
+            for input in inputs.drain(..) {
+                if reply_tx.send(input).await.is_err() {
+                    return;
                 }
             }
         }
     }
 
-    async fn start_timeout(tx: mpsc::Sender<Input>, timeout: Duration) {
-        let tx = tx.clone();
-        tokio::time::sleep(timeout).await;
-        let _ = tx.send(Input::Timeout).await;
-    }
-
     async fn run(self) {
         let Self {
             size,
+            ip_header,
+            pattern,
+            rate_limit,
+            batch_size,
+            grace_window,
+            counters,
             mut command_rx,
-            command_tx,
-            mut tx,
+            mut reply_rx,
+            listeners,
+            diagnostics,
+            datagram,
+            tx,
+            tx6,
+            timestamping,
+            timestamping6,
         } = self;
-        let mut index = 0u16;
-        let mut timer_running = false;
-        let mut ongoing = BTreeMap::new();
-        let mut last_ttl = 0;
+        let mut ids = IdAllocator { counter: 0 };
+        let mut ongoing: BTreeMap<RequestKey, OngoingRequest> = BTreeMap::new();
+        let mut deadlines = Deadlines::new();
+        let mut completed = CompletedRequests::new(grace_window);
+        // IP_MTU_DISCOVER of the IPv4 socket
+        let mut last_dont_fragment = None;
+        // Per request random numbers, so that replies to another pinger or to a previous
+        // request using the same identifier are not mistaken for ours
+        let mut nonces = Random::new();
+        let mut pacer = Pacer::new(rate_limit);
+        let mut batch = Batch::new(batch_size);
+        let socket_of = |ipv6: bool| if ipv6 { tx6.as_deref().unwrap() } else { &*tx };
+        let mut done = None;
 
-        while let Some(input) = command_rx.recv().await {
+        loop {
+            counters.set_in_flight(ongoing.len());
+            counters.set_paced(pacer.queue.len());
+            // Queued requests go first, as far as the rate limit allows
+            let input = match pacer.pop(Instant::now()) {
+                Some(request) => Input::PingRequest(request),
+                // Send the pending batch once every queued command is handled
+                None if !batch.probes.is_empty() => match queued_input(&mut command_rx).await {
+                    Some(Input::PingRequest(request)) => {
+                        pacer.push(request);
+                        continue;
+                    }
+                    Some(input) => input,
+                    None => {
+                        batch.flush(
+                            socket_of(batch.ipv6),
+                            &mut ongoing,
+                            &mut deadlines,
+                            &counters,
+                        );
+                        continue;
+                    }
+                },
+                None => {
+                    let wake = earliest(deadlines.next(), pacer.next());
+                    match next_input(&mut command_rx, &mut reply_rx, wake, Input::Timeout).await {
+                        Some(Input::PingRequest(request)) => {
+                            pacer.push(request);
+                            continue;
+                        }
+                        Some(input) => input,
+                        None => break,
+                    }
+                }
+            };
             match input {
                 Input::PingRequest(request) => {
-                    let mut vec: Vec<u8> = vec![0; size];
+                    let (id, sn) =
+                        match ids.allocate(datagram, request.addr, request.flow_id, &ongoing) {
+                            Some(id) => id,
+                            None => {
+                                let _ = request
+                                    .response_channel
+                                    .send(Err(PingError::NoFreeIdentifier));
+                                continue;
+                            }
+                        };
+                    let options = request.options;
+                    let size = options.size.map_or(size, usize::from);
+                    // Room for the 2 bytes tuning the checksum
+                    let min_size = echo_request::EchoRequestPacket::minimum_packet_size()
+                        + options.checksum.map_or(0, |_| 2);
+                    let mut vec: Vec<u8> = vec![0; size.max(min_size)];
                     let mut echo_packet =
                         echo_request::MutableEchoRequestPacket::new(&mut vec[..]).unwrap();
-                    let id = ((index as u32 + request.flow_id as u32) % 0x1_00_00) as u16;
-                    let sn = 0xFF_FF - index;
                     echo_packet.set_identifier(id);
                     echo_packet.set_sequence_number(sn);
-                    echo_packet.set_icmp_type(icmp::IcmpTypes::EchoRequest);
-                    let csum = pnet::util::checksum(echo_packet.packet(), 1);
-                    echo_packet.set_checksum(csum);
 
-                    if request.ttl != last_ttl {
-                        tx.set_ttl(request.ttl).unwrap();
-                        last_ttl = request.ttl;
+                    // Wall clock time of the probe, as close to the kernel one as possible
+                    let before = SystemTime::now();
+                    let payload_size = echo_packet.payload().len();
+                    let mut payload = build_payload(payload_size, nonces.next(), before, &pattern);
+                    echo_packet.set_payload(&payload);
+                    let ipv6 = request.addr.is_ipv6();
+                    echo_packet.set_icmp_type(if ipv6 {
+                        icmp::IcmpType(icmpv6::Icmpv6Types::EchoRequest.0)
+                    } else {
+                        icmp::IcmpTypes::EchoRequest
+                    });
+                    if let Some(checksum) = options.checksum {
+                        tune_checksum(echo_packet.packet_mut(), checksum);
+                        payload[..2].copy_from_slice(&echo_packet.payload()[..2]);
                     }
-                    if tx.send_to(echo_packet, IpAddr::V4(request.addr)).is_err() {
+                    let (socket, socket_timestamping) = match request.addr {
+                        IpAddr::V4(_) => {
+                            let csum = pnet::util::checksum(echo_packet.packet(), 1);
+                            echo_packet.set_checksum(csum);
+                            (&*tx, timestamping)
+                        }
+                        // The kernel computes ICMPv6 checksums itself, as they depend on the
+                        // source address.
+                        IpAddr::V6(_) => match tx6.as_deref() {
+                            Some(tx6) => (tx6, timestamping6),
+                            None => {
+                                counters.send_failure(1);
+                                let _ = request
+                                    .response_channel
+                                    .send(Err(PingError::FailedToSendPacket));
+                                continue;
+                            }
+                        },
+                    };
+                    let packet = match request.addr {
+                        IpAddr::V4(addr) if ip_header => build_ipv4_packet(addr, &options, &vec),
+                        _ => vec,
+                    };
+                    let key = request_key(datagram, request.addr, id, sn);
+
+                    // Packets built with their IP header already carry every field
+                    let packet_options = if ipv6 || !ip_header {
+                        socket::PacketOptions {
+                            ttl: Some(options.ttl),
+                            tos: Some(options.tos()),
+                            dont_fragment: options.dont_fragment.filter(|_| ipv6),
+                        }
+                    } else {
+                        socket::PacketOptions::default()
+                    };
+                    let set_dont_fragment =
+                        !ipv6 && !ip_header && options.dont_fragment != last_dont_fragment;
+                    if !batch.probes.is_empty() && (batch.ipv6 != ipv6 || set_dont_fragment) {
+                        batch.flush(
+                            socket_of(batch.ipv6),
+                            &mut ongoing,
+                            &mut deadlines,
+                            &counters,
+                        );
+                    }
+                    if set_dont_fragment {
+                        if socket.set_dont_fragment(options.dont_fragment).is_err() {
+                            counters.send_failure(1);
+                            let _ = request
+                                .response_channel
+                                .send(Err(PingError::FailedToSendPacket));
+                            continue;
+                        }
+                        last_dont_fragment = options.dont_fragment;
+                    }
+
+                    if batch_size > 1 {
+                        batch.ipv6 = ipv6;
+                        batch.timestamping = socket_timestamping;
+                        batch.probes.push(Probe {
+                            key,
+                            addr: request.addr,
+                            packet,
+                            options: packet_options,
+                            payload: payload.into(),
+                            request,
+                        });
+                        if batch.probes.len() >= batch_size {
+                            batch.flush(socket, &mut ongoing, &mut deadlines, &counters);
+                        }
+                        continue;
+                    }
+
+                    if socket
+                        .send_to(&packet, request.addr, &packet_options)
+                        .is_err()
+                    {
                         // TODO Signal error?
+                        counters.send_failure(1);
                         let _ = request
                             .response_channel
                             .send(Err(PingError::FailedToSendPacket));
-                    } else {
-                        let start = Instant::now();
-                        ongoing.insert(
-                            (request.addr, id),
-                            OngoingRequest {
+                        continue;
+                    }
+                    counters.sent(1);
+                    let kernel_sent_at =
+                        if socket_timestamping == socket::Timestamping::SendAndReceive {
+                            send_timestamp(socket, before)
+                        } else {
+                            None
+                        };
+                    let start = Instant::now();
+                    let stop = start + request.timeout;
+                    deadlines.push(stop, key);
+                    ongoing.insert(
+                        key,
+                        OngoingRequest {
+                            sent: SendTime {
                                 start,
-                                stop: start + request.timeout,
-                                response_channel: request.response_channel,
+                                sent_at: before,
+                                kernel_sent_at,
                             },
-                        );
-                        if index == 0xFF_FF {
-                            index = 0;
-                        } else {
-                            index += 1;
-                        }
-                        if !timer_running {
-                            tokio::spawn(Self::start_timeout(command_tx.clone(), request.timeout));
-                            timer_running = true;
-                        }
-                    }
+                            stop,
+                            payload: payload.into(),
+                            response_channel: request.response_channel,
+                        },
+                    );
                 }
-                Input::PingResponse(response) => {
-                    if let Some(ongoing) = ongoing.remove(&(response.destination, response.id)) {
-                        let duration = if response.stop > ongoing.start {
-                            response.stop.duration_since(ongoing.start)
-                        } else {
-                            Duration::from_nanos(10)
+                Input::PingResponse(response, payload) => {
+                    let key = request_key(datagram, response.destination, response.id, response.sn);
+                    let check = match ongoing.get(&key) {
+                        Some(ongoing) => check_echo(&ongoing.payload, &payload),
+                        None => EchoCheck::Stale,
+                    };
+                    if check == EchoCheck::Stale {
+                        let diagnostic = match completed.recognize(&key, &payload, response.stop) {
+                            Some(Straggler::Late { stop, sent }) => {
+                                counters.late();
+                                Diagnostic::LateReply {
+                                    responder: response.responder,
+                                    id: response.id,
+                                    seq: response.sn,
+                                    late_by: response.stop.saturating_duration_since(stop),
+                                    rtt: timing(&sent, &response).rtt,
+                                }
+                            }
+                            Some(Straggler::Duplicate { sent }) => {
+                                counters.duplicate();
+                                Diagnostic::DuplicateReply {
+                                    responder: response.responder,
+                                    id: response.id,
+                                    seq: response.sn,
+                                    rtt: timing(&sent, &response).rtt,
+                                }
+                            }
+                            None => {
+                                counters.unmatched();
+                                Diagnostic::UnmatchedReply {
+                                    responder: response.responder,
+                                    id: response.id,
+                                    seq: response.sn,
+                                }
+                            }
                         };
-                        let _ = ongoing.response_channel.send(Ok(duration));
+                        let _ = diagnostics.send(diagnostic);
+                        continue;
                     }
-                }
-                Input::PingTimeExceeded(response) => {
-                    if let Some(ongoing) = ongoing.remove(&(response.destination, response.id)) {
-                        let latency = if response.stop > ongoing.start {
-                            response.stop.duration_since(ongoing.start)
-                        } else {
-                            Duration::from_nanos(10)
+                    if let Some(ongoing) = ongoing.remove(&key) {
+                        completed.push(key, response.stop, ongoing.sent, false, &ongoing.payload);
+                        let timing = timing(&ongoing.sent, &response);
+                        counters.matched(timing.rtt);
+                        let reply = PingReply {
+                            responder: response.responder,
+                            destination: response.destination,
+                            id: response.id,
+                            seq: response.sn,
+                            sent_at: timing.sent_at,
+                            received_at: timing.received_at,
+                            rtt: timing.rtt,
+                            clock: timing.clock,
+                            payload_len: response.size,
+                            ip: response.ip,
                         };
+                        let _ = ongoing.response_channel.send(match check {
+                            EchoCheck::Invalid(error) => Err(PingError::InvalidEcho {
+                                reply: Box::new(reply),
+                                error,
+                            }),
+                            _ => Ok(reply),
+                        });
+                    }
+                }
+                Input::PingTimeExceeded(response, mpls) => {
+                    if let Some(ongoing) = ongoing.remove(&request_key(
+                        datagram,
+                        response.destination,
+                        response.id,
+                        response.sn,
+                    )) {
+                        let latency = timing(&ongoing.sent, &response).rtt;
+                        counters.error();
                         let _ = ongoing.response_channel.send(Err(PingError::TimeExceeded {
                             addr: response.responder,
                             latency,
+                            ip: response.ip,
+                            mpls,
                         }));
                     }
                 }
                 Input::IcmpError { id, code, ty, data } => {
-                    if let Some(ongoing) = ongoing.remove(&(id.destination, id.id)) {
-                        let latency = if id.stop > ongoing.start {
-                            id.stop.duration_since(ongoing.start)
-                        } else {
-                            Duration::from_nanos(10)
-                        };
-                        let _ = ongoing.response_channel.send(Err(PingError::IcmpError {
+                    if let Some(ongoing) =
+                        ongoing.remove(&request_key(datagram, id.destination, id.id, id.sn))
+                    {
+                        let latency = timing(&ongoing.sent, &id).rtt;
+                        counters.error();
+                        let _ = ongoing.response_channel.send(Err(PingError::from_icmp(
+                            id.responder,
                             ty,
                             code,
                             data,
-                            responder: id.responder,
+                            !datagram,
                             latency,
-                        }));
+                            id.ip,
+                        )));
                     }
                 }
                 Input::Timeout => {
                     let now = Instant::now();
-                    let lost: Vec<_> = ongoing
-                        .iter()
-                        .filter(|(_, v)| now > v.stop)
-                        .map(|(k, _)| *k)
-                        .collect();
-                    for k in lost.into_iter() {
-                        let v = ongoing.remove(&k).unwrap();
-                        let _ = v.response_channel.send(Err(PingError::Timeout));
-                    }
-                    if !ongoing.is_empty() {
-                        let earliest_timeout = ongoing.values().map(|v| v.stop).min().unwrap();
-                        let delay = earliest_timeout.duration_since(now);
-                        tokio::spawn(Self::start_timeout(command_tx.clone(), delay));
-                    } else {
-                        timer_running = false;
+                    while let Some(key) = deadlines.pop_expired(now) {
+                        // The key may have been reused by a later request
+                        if matches!(ongoing.get(&key), Some(v) if v.stop <= now) {
+                            let v = ongoing.remove(&key).unwrap();
+                            completed.push(key, v.stop, v.sent, true, &v.payload);
+                            counters.timeout();
+                            let _ = v.response_channel.send(Err(PingError::Timeout {
+                                echo: Some((key.1, key.2)),
+                            }));
+                        }
                     }
                 }
-                Input::Stop => break,
+                Input::SetRateLimit(limit) => pacer.set_limit(limit),
+                Input::Stop(tx) => {
+                    done = Some(tx);
+                    break;
+                }
             }
         }
+
+        // Every pinger is gone, or one of them shut the backend down
+        let requests = pacer
+            .queue
+            .drain(..)
+            .chain(batch.probes.drain(..).map(|probe| probe.request))
+            .map(|request| request.response_channel);
+        let ongoing = ongoing.into_values().map(|v| v.response_channel);
+        for response_channel in requests.chain(ongoing) {
+            let _ = response_channel.send(Err(PingError::BackendClosed));
+        }
+        counters.set_in_flight(0);
+        counters.set_paced(0);
+        // The listeners return once the reply channel is closed, and release the sockets
+        drop(reply_rx);
+        for listener in listeners {
+            let _ = listener.await;
+        }
+        drop((tx, tx6));
+        if let Some(done) = done {
+            let _ = done.send(());
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct Pinger {
     command_tx: mpsc::Sender<Input>,
+    counters: Arc<Counters>,
+    diagnostics: broadcast::Sender<Diagnostic>,
+    // Raw or Datagram
+    socket_mode: SocketMode,
+    grace_window: Duration,
 }
 
 impl Pinger {
     // initialize the pinger and start the icmp and icmpv6 listeners
     pub fn new(size: u16, parallelism: usize) -> Result<Self, std::io::Error> {
-        let (command_tx, command_rx) = mpsc::channel(parallelism);
-        PingerBackend::start(size, command_rx, command_tx.clone())?;
-        Ok(Self { command_tx })
+        Self::with_options(PingerOptions {
+            size,
+            parallelism,
+            ..Default::default()
+        })
+    }
+
+    pub fn with_options(options: PingerOptions) -> Result<Self, std::io::Error> {
+        if matches!(options.rate_limit, Some(limit) if !limit.is_valid()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Rate limit of 0 packets per second",
+            ));
+        }
+        let (command_tx, command_rx) = mpsc::channel(options.parallelism);
+        let counters = Arc::new(Counters::new());
+        let (diagnostics, _) = broadcast::channel(DIAGNOSTICS_CAPACITY);
+        let grace_window = options.grace_window;
+        let socket_mode =
+            PingerBackend::start(options, command_rx, counters.clone(), diagnostics.clone())?;
+        Ok(Self {
+            command_tx,
+            counters,
+            diagnostics,
+            socket_mode,
+            grace_window,
+        })
+    }
+
+    // Pinger sending through `transport`, which hands the packets it receives to `incoming`.
+    // The socket options are ignored.
+    pub fn with_transport(
+        options: PingerOptions,
+        transport: Arc<dyn Transport>,
+        incoming: mpsc::Receiver<Incoming>,
+    ) -> Self {
+        let (command_tx, command_rx) = mpsc::channel(options.parallelism);
+        let counters = Arc::new(Counters::new());
+        let (diagnostics, _) = broadcast::channel(DIAGNOSTICS_CAPACITY);
+        let grace_window = options.grace_window;
+        PingerBackend::start_with_transport(
+            options,
+            transport,
+            incoming,
+            command_rx,
+            counters.clone(),
+            diagnostics.clone(),
+        );
+        Self {
+            command_tx,
+            counters,
+            diagnostics,
+            // Transports deliver packets the way raw sockets do
+            socket_mode: SocketMode::Raw,
+            grace_window,
+        }
+    }
+
+    pub fn socket_mode(&self) -> SocketMode {
+        self.socket_mode
+    }
+
+    // How long the backend remembers its answered and timed out requests
+    pub fn grace_window(&self) -> Duration {
+        self.grace_window
+    }
+
+    // Packets sent and received since the pinger was created. Received packets include
+    // the ICMP traffic of other programs.
+    pub fn throughput(&self) -> Throughput {
+        self.counters.throughput()
     }
 
+    // Counters of the backend, shared by every clone of the pinger
+    pub fn metrics(&self) -> PingerMetrics {
+        let capacity = self.command_tx.max_capacity();
+        self.counters
+            .metrics(capacity - self.command_tx.capacity(), capacity)
+    }
+
+    // Events from now on. Receivers falling behind by more than DIAGNOSTICS_CAPACITY events
+    // lose the oldest ones.
+    pub fn diagnostics(&self) -> broadcast::Receiver<Diagnostic> {
+        self.diagnostics.subscribe()
+    }
+
+    // `flow_id` has no effect on datagram sockets, whose echo identifier is set by the kernel
     pub async fn ping(
         &self,
-        addr: Ipv4Addr,
+        addr: IpAddr,
         ttl: u8,
         timeout: Duration,
         flow_id: u16,
-    ) -> Result<Duration, PingError> {
+    ) -> Result<PingReply, PingError> {
+        self.ping_with_options(addr, ProbeOptions::new().ttl(ttl), timeout, flow_id)
+            .await
+    }
+
+    pub async fn ping_with_options(
+        &self,
+        addr: IpAddr,
+        options: ProbeOptions,
+        timeout: Duration,
+        flow_id: u16,
+    ) -> Result<PingReply, PingError> {
         let (tx, rx) = oneshot::channel();
         if self
             .command_tx
             .send(Input::PingRequest(PingRequest {
                 addr,
-                ttl,
+                options,
                 flow_id,
                 timeout,
                 response_channel: tx,
@@ -356,8 +1712,382 @@ impl Pinger {
         rx.await.unwrap_or(Err(PingError::BackendClosed))
     }
 
-    // TODO This has to be called to stop the backend thread
-    pub async fn stop(self) {
-        let _ = self.command_tx.send(Input::Stop).await;
+    // Applies to the requests still waiting for the previous limit
+    pub async fn set_rate_limit(&self, limit: Option<RateLimit>) -> Result<(), PingError> {
+        if matches!(limit, Some(limit) if !limit.is_valid()) {
+            return Err(PingError::InvalidRateLimit);
+        }
+        self.command_tx
+            .send(Input::SetRateLimit(limit))
+            .await
+            .map_err(|_| PingError::BackendClosed)
+    }
+
+    // Stop the backend, for every clone of the pinger, and wait until its sockets are closed.
+    // Dropping the last clone does the same in the background. Either way, the requests still
+    // waiting fail with `BackendClosed`.
+    pub async fn shutdown(self) {
+        let (tx, rx) = oneshot::channel();
+        if self.command_tx.send(Input::Stop(tx)).await.is_ok() {
+            let _ = rx.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::simulation::{Hop, SimulatedNetwork};
+    use super::*;
+
+    #[test]
+    fn checksum_is_tuned() {
+        for &checksum in &[0, 1, 0x12_34, 0xFF_FE] {
+            for (sn, &size) in [2, 3, 56].iter().enumerate() {
+                let mut vec = vec![0; 8 + size];
+                let mut packet = echo_request::MutableEchoRequestPacket::new(&mut vec).unwrap();
+                packet.set_icmp_type(icmp::IcmpTypes::EchoRequest);
+                packet.set_identifier(0x80_00);
+                packet.set_sequence_number(sn as u16);
+                packet.set_payload(&build_payload(size, 42, SystemTime::now(), b"ab"));
+                tune_checksum(&mut vec, checksum);
+                assert_eq!(pnet::util::checksum(&vec, 1), checksum);
+            }
+        }
+    }
+
+    #[test]
+    fn echo_validation() {
+        let sent = build_payload(56, 42, SystemTime::now(), b"ab");
+        assert_eq!(sent.len(), 56);
+        assert_eq!(&sent[16..20], b"abab");
+        assert_eq!(check_echo(&sent, &sent), EchoCheck::Valid);
+
+        let mut echoed = sent.clone();
+        echoed[0] ^= 1;
+        assert_eq!(check_echo(&sent, &echoed), EchoCheck::Stale);
+
+        let mut echoed = sent.clone();
+        echoed[30] ^= 1;
+        assert_eq!(
+            check_echo(&sent, &echoed),
+            EchoCheck::Invalid(EchoError::Corrupted { offset: 30 })
+        );
+
+        assert_eq!(
+            check_echo(&sent, &sent[..20]),
+            EchoCheck::Invalid(EchoError::Truncated {
+                sent: 56,
+                echoed: 20
+            })
+        );
+    }
+
+    #[test]
+    fn late_and_duplicate_replies_are_recognized() {
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+        let start = Instant::now();
+        let sent = SendTime {
+            start,
+            sent_at: SystemTime::now(),
+            kernel_sent_at: None,
+        };
+        let payload = build_payload(56, 42, sent.sent_at, &[]);
+        let key = |sn| request_key(false, addr, 1, sn);
+        let mut completed = CompletedRequests::new(GRACE_WINDOW);
+        let answered = start + GRACE_WINDOW / 2;
+        completed.push(key(1), answered, sent, false, &payload);
+        // Timed out before the other one was answered
+        completed.push(key(0), start, sent, true, &payload);
+
+        // Another request's reply, using the same identifiers
+        let other = build_payload(56, 43, SystemTime::now(), &[]);
+        assert_eq!(completed.recognize(&key(0), &other, answered), None);
+        assert_eq!(
+            completed.recognize(&key(0), &payload, answered),
+            Some(Straggler::Late { stop: start, sent })
+        );
+        // Copies of a late reply, and of an answer, are duplicates
+        assert_eq!(
+            completed.recognize(&key(0), &payload, answered),
+            Some(Straggler::Duplicate { sent })
+        );
+        assert_eq!(
+            completed.recognize(&key(1), &payload, answered),
+            Some(Straggler::Duplicate { sent })
+        );
+
+        // Forgotten after a while, oldest first
+        let later = start + GRACE_WINDOW * 2;
+        assert_eq!(completed.recognize(&key(1), &payload, later), None);
+        completed.push(key(2), start + GRACE_WINDOW * 5 / 4, sent, true, &payload);
+        assert!(!completed.requests.contains_key(&key(0)));
+        assert!(completed.requests.contains_key(&key(1)));
+    }
+
+    #[test]
+    fn ids_in_use_are_skipped() {
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+        let mut ongoing = BTreeMap::new();
+        ongoing.insert(request_key(false, addr, 7, 0), ());
+        ongoing.insert(request_key(false, addr, 7, 1), ());
+        let mut ids = IdAllocator { counter: 0 };
+        assert_eq!(ids.allocate(false, addr, 7, &ongoing), Some((7, 2)));
+
+        // The kernel picks the identifier of datagram sockets
+        ongoing.insert(request_key(true, addr, 0, 3), ());
+        assert_eq!(ids.allocate(true, addr, 9, &ongoing), Some((9, 4)));
+
+        for sn in 0..=0xFF_FF {
+            ongoing.insert(request_key(true, addr, 0, sn), ());
+        }
+        assert_eq!(ids.allocate(true, addr, 9, &ongoing), None);
+    }
+
+    #[test]
+    fn unreachable_errors_are_parsed() {
+        let router: IpAddr = "192.0.2.1".parse().unwrap();
+        let destination = "198.51.100.1".parse().unwrap();
+        let mut probe = vec![8, 0, 0, 0, 0, 7, 0, 9];
+        probe.extend_from_slice(&build_payload(56, 42, SystemTime::now(), &[]));
+        let quoted = build_ipv4_packet(destination, &ProbeOptions::new().ttl(3), &probe);
+        // Fragmentation needed, next-hop MTU 1400
+        let mut message = vec![3, 4, 0, 0, 0, 0, 0x05, 0x78];
+        message.extend_from_slice(&quoted);
+
+        let packet = icmp::IcmpPacket::new(&message).unwrap();
+        let (response, id) = parse_icmpv4(&packet, router, None).unwrap();
+        assert_eq!((id.id, id.sn), (7, 9));
+        // The same error about a UDP datagram is none of the pinger's business
+        let mut other = message.clone();
+        other[8 + 9] = IpNextHeaderProtocols::Udp.0;
+        let packet = icmp::IcmpPacket::new(&other).unwrap();
+        assert!(parse_icmpv4(&packet, router, None).is_none());
+        assert!(!expected_icmpv4(&packet));
+        let error = match response.build_input(id) {
+            Input::IcmpError { id, ty, code, data } => {
+                PingError::from_icmp(id.responder, ty, code, data, true, Duration::ZERO, id.ip)
+            }
+            input => panic!("{:?}", input),
+        };
+        match error {
+            PingError::Unreachable { code, quoted, .. } => {
+                assert_eq!(code, UnreachableCode::FragmentationNeeded);
+                assert_eq!(
+                    quoted,
+                    QuotedPacket {
+                        ttl: Some(3),
+                        length: Some(84),
+                        next_hop_mtu: Some(1400),
+                    }
+                );
+            }
+            error => panic!("{:?}", error),
+        }
+
+        // Datagram sockets only get the probe back
+        let report = socket::ErrorReport {
+            len: probe.len(),
+            destination: IpAddr::V4(destination),
+            offender: router,
+            icmpv6: false,
+            ty: 3,
+            code: 13,
+            info: 0,
+            timestamp: None,
+        };
+        let error = match parse_error_report(&report, &probe).unwrap() {
+            Input::IcmpError { id, ty, code, data } => {
+                PingError::from_icmp(id.responder, ty, code, data, false, Duration::ZERO, id.ip)
+            }
+            input => panic!("{:?}", input),
+        };
+        assert!(matches!(
+            error,
+            PingError::Unreachable {
+                code: UnreachableCode::AdminProhibited,
+                quoted: QuotedPacket { ttl: None, .. },
+                ..
+            }
+        ));
+
+        // Source route failed is left raw
+        assert_eq!(UnreachableCode::from_icmp(false, 3, 5), None);
+        assert_eq!(
+            UnreachableCode::from_icmp(true, 2, 0),
+            Some(UnreachableCode::FragmentationNeeded)
+        );
+    }
+
+    #[tokio::test]
+    async fn simulated_network() {
+        let router: IpAddr = "198.51.100.1".parse().unwrap();
+        let host: IpAddr = "203.0.113.1".parse().unwrap();
+        let host6: IpAddr = "2001:db8:1::1".parse().unwrap();
+        let latency = Duration::from_millis(1);
+        let timeout = Duration::from_millis(100);
+        let limit = RateLimit { pps: 1, burst: 2 };
+        let network = SimulatedNetwork::new(1)
+            .route(
+                Hop::new(host, latency),
+                vec![vec![Hop::new(router, latency).rate_limit(limit)]],
+            )
+            .route(Hop::new(host6, latency), vec![vec![Hop::silent(latency)]]);
+
+        let pinger = network.pinger(PingerOptions {
+            ip_header: true,
+            ..Default::default()
+        });
+        let reply = pinger.ping(host, 64, timeout, 0).await.unwrap();
+        assert_eq!(reply.responder, host);
+        assert!(reply.rtt >= latency * 4);
+        assert_eq!(reply.ip.reply.map(|ip| ip.ttl), Some(62));
+        // The router answers twice, then hits its rate limit
+        for _ in 0..2 {
+            match pinger.ping(host, 1, timeout, 0).await {
+                Err(PingError::TimeExceeded { addr, ip, .. }) => {
+                    assert_eq!(addr, router);
+                    assert_eq!(ip.quoted.map(|ip| ip.ttl), Some(1));
+                }
+                res => panic!("{:?}", res),
+            }
+        }
+        let res = pinger.ping(host, 1, timeout, 0).await;
+        assert!(matches!(res, Err(PingError::Timeout { .. })), "{:?}", res);
+        // No route
+        let res = pinger
+            .ping("192.0.2.1".parse().unwrap(), 64, timeout, 0)
+            .await;
+        assert!(matches!(res, Err(PingError::Timeout { .. })), "{:?}", res);
+        let res = pinger
+            .set_rate_limit(Some(RateLimit { pps: 0, burst: 1 }))
+            .await;
+        assert!(matches!(res, Err(PingError::InvalidRateLimit)), "{:?}", res);
+        pinger.shutdown().await;
+
+        let pinger = network.pinger(PingerOptions::default());
+        let reply = pinger.ping(host6, 2, timeout, 0).await.unwrap();
+        assert_eq!(reply.responder, host6);
+        let res = pinger.ping(host6, 1, timeout, 0).await;
+        assert!(matches!(res, Err(PingError::Timeout { .. })), "{:?}", res);
+        pinger.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn metrics_count_requests() {
+        let router: IpAddr = "198.51.100.1".parse().unwrap();
+        let host: IpAddr = "203.0.113.1".parse().unwrap();
+        let latency = Duration::from_millis(1);
+        let timeout = Duration::from_millis(100);
+        let network = SimulatedNetwork::new(1).route(
+            Hop::new(host, latency),
+            vec![vec![Hop::new(router, latency)]],
+        );
+        let pinger = network.pinger(PingerOptions::default());
+        for _ in 0..3 {
+            pinger.ping(host, 64, timeout, 0).await.unwrap();
+        }
+        assert!(pinger.ping(host, 1, timeout, 0).await.is_err());
+        let res = pinger
+            .ping("192.0.2.1".parse().unwrap(), 64, timeout, 0)
+            .await;
+        assert!(matches!(res, Err(PingError::Timeout { .. })), "{:?}", res);
+
+        let metrics = pinger.metrics();
+        assert_eq!(metrics.sent, 5);
+        assert_eq!(metrics.received, 4);
+        assert_eq!(metrics.matched, 3);
+        assert_eq!(metrics.errors, 1);
+        assert_eq!(metrics.timeouts, 1);
+        assert_eq!(
+            (metrics.send_failures, metrics.unmatched, metrics.late),
+            (0, 0, 0)
+        );
+        assert_eq!((metrics.in_flight, metrics.queued), (0, 0));
+        assert_eq!(
+            metrics.command_capacity,
+            PingerOptions::default().parallelism
+        );
+        assert_eq!(metrics.rtt.total(), 3);
+        assert!(metrics.rtt.quantile(1.) >= Some(latency * 4));
+
+        // Requests held back by the rate limit are queued too. The first one goes out at once.
+        let limit = RateLimit { pps: 1, burst: 1 };
+        pinger.set_rate_limit(Some(limit)).await.unwrap();
+        for _ in 0..3 {
+            let pinger = pinger.clone();
+            tokio::spawn(async move { pinger.ping(host, 64, timeout, 0).await });
+        }
+        // Well before the next token, a second away
+        let queued = async {
+            while pinger.metrics().queued != 2 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_millis(500), queued)
+            .await
+            .expect("requests not queued");
+        pinger.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn stragglers_are_reported() {
+        let slow: IpAddr = "203.0.113.1".parse().unwrap();
+        let duplicating: IpAddr = "203.0.113.2".parse().unwrap();
+        let network = SimulatedNetwork::new(1)
+            .route(Hop::new(slow, Duration::from_millis(20)), vec![])
+            .route(
+                Hop::new(duplicating, Duration::from_millis(1)).duplicate(1.),
+                vec![],
+            );
+        let pinger = network.pinger(PingerOptions::default());
+        let mut diagnostics = pinger.diagnostics();
+
+        let res = pinger.ping(slow, 64, Duration::from_millis(10), 0).await;
+        assert!(matches!(res, Err(PingError::Timeout { .. })), "{:?}", res);
+        match diagnostics.recv().await.unwrap() {
+            Diagnostic::LateReply { responder, rtt, .. } => {
+                assert_eq!(responder, slow);
+                assert!(rtt >= Duration::from_millis(40), "{:?}", rtt);
+            }
+            diagnostic => panic!("{:?}", diagnostic),
+        }
+
+        let reply = pinger
+            .ping(duplicating, 64, Duration::from_millis(100), 0)
+            .await
+            .unwrap();
+        match diagnostics.recv().await.unwrap() {
+            Diagnostic::DuplicateReply { id, seq, rtt, .. } => {
+                assert_eq!((id, seq), (reply.id, reply.seq));
+                assert!(rtt >= reply.rtt);
+            }
+            diagnostic => panic!("{:?}", diagnostic),
+        }
+
+        let metrics = pinger.metrics();
+        assert_eq!((metrics.late, metrics.duplicates), (1, 1));
+        assert_eq!(metrics.unmatched, 0);
+        pinger.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn simulated_losses() {
+        let host: IpAddr = "203.0.113.1".parse().unwrap();
+        let latency = Duration::from_millis(1);
+        let network = SimulatedNetwork::new(7).route(Hop::new(host, latency).loss(0.5), vec![]);
+        let pinger = network.pinger(PingerOptions::default());
+        let mut answered = 0;
+        for _ in 0..20 {
+            if pinger
+                .ping(host, 64, Duration::from_millis(50), 0)
+                .await
+                .is_ok()
+            {
+                answered += 1;
+            }
+        }
+        // The same half on every run
+        assert_eq!(answered, 10);
+        pinger.shutdown().await;
     }
 }
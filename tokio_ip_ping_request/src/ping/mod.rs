@@ -1,37 +1,1064 @@
 pub mod icmp;
+pub mod simulation;
+mod socket;
 pub mod tcp;
+pub mod transport;
+pub mod tun;
+pub mod udp;
 
 use pnet::packet;
-use std::net::Ipv4Addr;
-use std::time::Duration;
-use tokio::sync::oneshot;
+use pnet::packet::ipv4::Ipv4Packet;
+use std::cmp::Reverse;
+use std::collections::hash_map::RandomState;
+use std::collections::{BinaryHeap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use self::socket::Socket;
+
+const RECEIVE_BUFFER_SIZE: usize = 4 << 20;
+// Probes can be as large as IP allows, and so can their replies
+const MAX_PACKET_SIZE: usize = 1 << 16;
+// ICMPv4 errors fit in 576 bytes, and the TCP and UDP pingers only need the headers of the
+// other packets
+const HEADERS_SIZE: usize = 576;
+const LISTENER_BATCH_SIZE: usize = 32;
 
 pub type IcmpCode = packet::icmp::IcmpCode;
 pub type IcmpType = packet::icmp::IcmpType;
 
 #[derive(Debug)]
 pub struct PingRequest {
-    addr: Ipv4Addr,
-    ttl: u8,
+    addr: IpAddr,
+    options: ProbeOptions,
     flow_id: u16,
     timeout: Duration,
-    response_channel: oneshot::Sender<Result<Duration, PingError>>,
+    response_channel: oneshot::Sender<Result<PingReply, PingError>>,
+}
+
+// IP and ICMP settings of a single probe, from ProbeOptions::new().ttl(...).size(...)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeOptions {
+    ttl: u8,
+    size: Option<u16>,
+    dscp: u8,
+    ecn: u8,
+    dont_fragment: Option<bool>,
+    checksum: Option<u16>,
+}
+
+impl Default for ProbeOptions {
+    fn default() -> Self {
+        Self {
+            ttl: 64,
+            size: None,
+            dscp: 0,
+            ecn: 0,
+            dont_fragment: None,
+            checksum: None,
+        }
+    }
+}
+
+impl ProbeOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ttl(mut self, ttl: u8) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    // Size in bytes of the ICMP packet, instead of the pinger's
+    pub fn size(mut self, size: u16) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    // Differentiated services code point, on 6 bits
+    pub fn dscp(mut self, dscp: u8) -> Self {
+        self.dscp = dscp & 0x3F;
+        self
+    }
+
+    // Explicit congestion notification, on 2 bits
+    pub fn ecn(mut self, ecn: u8) -> Self {
+        self.ecn = ecn & 0x3;
+        self
+    }
+
+    // Set or clear the DF bit of IPv4 probes, or forbid the fragmentation of IPv6 ones by
+    // the host. By default the kernel sets it on probes below the path MTU, except with
+    // `ip_header`.
+    pub fn dont_fragment(mut self, dont_fragment: bool) -> Self {
+        self.dont_fragment = Some(dont_fragment);
+        self
+    }
+
+    // ICMP checksum of the probe, reached by tuning the first 2 bytes of its payload. Per flow
+    // load balancers hash it as they hash the ports of TCP and UDP, so probes with the same
+    // checksum take the same path. 0xFFFF is sent as 0. The kernel adds the addresses to
+    // IPv6 checksums, which then only stay the same.
+    pub fn checksum(mut self, checksum: u16) -> Self {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    pub fn get_ttl(&self) -> u8 {
+        self.ttl
+    }
+
+    pub fn get_size(&self) -> Option<u16> {
+        self.size
+    }
+
+    pub fn get_dscp(&self) -> u8 {
+        self.dscp
+    }
+
+    pub fn get_ecn(&self) -> u8 {
+        self.ecn
+    }
+
+    pub fn get_dont_fragment(&self) -> Option<bool> {
+        self.dont_fragment
+    }
+
+    pub fn get_checksum(&self) -> Option<u16> {
+        self.checksum
+    }
+
+    // IPv4 type of service, or IPv6 traffic class
+    fn tos(&self) -> u8 {
+        (self.dscp << 2) | self.ecn
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PingReply {
+    // Host that sent the echo reply
+    pub responder: IpAddr,
+    // Host the echo request was sent to
+    pub destination: IpAddr,
+    pub id: u16,
+    pub seq: u16,
+    pub sent_at: SystemTime,
+    pub received_at: SystemTime,
+    pub rtt: Duration,
+    // Clock `sent_at`, `received_at` and `rtt` were measured with
+    pub clock: ClockSource,
+    // Size in bytes of the echo reply payload
+    pub payload_len: usize,
+    pub ip: IpHeaders,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    // Instant::now() in the listener thread and in the backend
+    UserSpace,
+    // Kernel receive timestamp, user space send time
+    KernelReceive,
+    // Kernel software timestamps on both ends
+    Kernel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Header {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub ttl: u8,
+    pub identification: u16,
+    pub dscp: u8,
+    pub ecn: u8,
+    pub flags: u8,
+    pub total_length: u16,
+    pub protocol: u8,
+}
+
+impl Ipv4Header {
+    pub fn from_packet(packet: &Ipv4Packet) -> Self {
+        Self {
+            source: packet.get_source(),
+            destination: packet.get_destination(),
+            ttl: packet.get_ttl(),
+            identification: packet.get_identification(),
+            dscp: packet.get_dscp(),
+            ecn: packet.get_ecn(),
+            flags: packet.get_flags(),
+            total_length: packet.get_total_length(),
+            protocol: packet.get_next_level_protocol().0,
+        }
+    }
+}
+
+// IPv4 headers seen while matching an answer to its probe
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IpHeaders {
+    // Header of the answer. Only available from pingers receiving whole IP packets.
+    pub reply: Option<Ipv4Header>,
+    // Header of the probe, as quoted by an ICMP error
+    pub quoted: Option<Ipv4Header>,
+}
+
+// Why a Destination Unreachable message (ICMPv6: also Packet Too Big and unrecognized
+// next header) says the probe was dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnreachableCode {
+    // No route to the network
+    Network,
+    // No route to the host, or the host did not answer address resolution
+    Host,
+    // The host does not handle the protocol of the probe
+    Protocol,
+    // Nothing listens on the port of the probe
+    Port,
+    // The probe does not fit the next hop and must not be fragmented
+    FragmentationNeeded,
+    // Filtered, usually by a firewall
+    AdminProhibited,
+}
+
+impl UnreachableCode {
+    // None for other messages, which are reported as PingError::IcmpError
+    pub fn from_icmp(icmpv6: bool, ty: u8, code: u8) -> Option<Self> {
+        if icmpv6 {
+            match (ty, code) {
+                (1, 0) => Some(Self::Network),
+                (1, 1) | (1, 5) | (1, 6) => Some(Self::AdminProhibited),
+                (1, 3) => Some(Self::Host),
+                (1, 4) => Some(Self::Port),
+                (2, _) => Some(Self::FragmentationNeeded),
+                (4, 1) => Some(Self::Protocol),
+                _ => None,
+            }
+        } else if ty == 3 {
+            match code {
+                0 | 6 | 11 => Some(Self::Network),
+                1 | 7 | 12 => Some(Self::Host),
+                2 => Some(Self::Protocol),
+                3 => Some(Self::Port),
+                4 => Some(Self::FragmentationNeeded),
+                9 | 10 | 13 => Some(Self::AdminProhibited),
+                _ => None,
+            }
+        } else {
+            None
+        }
+    }
+}
+
+// The probe as the sender of an ICMP error saw it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotedPacket {
+    // TTL or hop limit left when the probe got there
+    pub ttl: Option<u8>,
+    // Length of the probe's IP packet
+    pub length: Option<u16>,
+    // MTU of the next hop, for FragmentationNeeded
+    pub next_hop_mtu: Option<u32>,
+}
+
+impl QuotedPacket {
+    // `message` is an ICMP(v6) error message. Errors reported on the error queue of datagram
+    // sockets do not quote the IP header of the probe, only the type specific data.
+    fn parse(icmpv6: bool, message: &[u8], header: bool, code: UnreachableCode) -> Self {
+        let mut quoted = Self::default();
+        if message.len() < 8 {
+            return quoted;
+        }
+        if code == UnreachableCode::FragmentationNeeded {
+            let info = u32::from_be_bytes([message[4], message[5], message[6], message[7]]);
+            // ICMPv4 only uses the low 16 bits, the others are unused
+            quoted.next_hop_mtu = Some(if icmpv6 { info } else { info & 0xFF_FF });
+        }
+        if !header {
+            return quoted;
+        }
+        if icmpv6 {
+            if let Some(ip) = packet::ipv6::Ipv6Packet::new(&message[8..]) {
+                quoted.ttl = Some(ip.get_hop_limit());
+                quoted.length = Some(ip.get_payload_length().saturating_add(40));
+            }
+        } else if let Some(ip) = Ipv4Packet::new(&message[8..]) {
+            quoted.ttl = Some(ip.get_ttl());
+            quoted.length = Some(ip.get_total_length());
+        }
+        quoted
+    }
+}
+
+// Entry of the MPLS label stack of a probe, as quoted by the router it expired on (RFC 4950)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MplsLabel {
+    pub label: u32,
+    // Traffic class, formerly experimental bits
+    pub exp: u8,
+    // Last entry of the stack
+    pub bottom: bool,
+    pub ttl: u8,
+}
+
+// MPLS label stacks carried by the extension structure of an ICMP(v6) error message
+// (RFC 4884)
+fn mpls_labels(icmpv6: bool, message: &[u8]) -> Vec<MplsLabel> {
+    let mut labels = vec![];
+    if message.len() < 8 {
+        return labels;
+    }
+    // Length of the quoted packet, in 64 bit words for ICMPv6 and 32 bit words for ICMPv4
+    let length = if icmpv6 {
+        message[4] as usize * 8
+    } else {
+        message[5] as usize * 4
+    };
+    // Routers predating RFC 4884 leave the length unset and pad the quoted packet to 128 bytes
+    let offset = 8 + if length == 0 { 128 } else { length };
+    let extensions = match message.get(offset..) {
+        Some(extensions) if extensions.len() >= 4 => extensions,
+        _ => return labels,
+    };
+    let checksum = u16::from_be_bytes([extensions[2], extensions[3]]);
+    if extensions[0] >> 4 != 2 || (checksum != 0 && pnet::util::checksum(extensions, 1) != checksum)
+    {
+        return labels;
+    }
+
+    let mut objects = &extensions[4..];
+    while objects.len() >= 4 {
+        let len = u16::from_be_bytes([objects[0], objects[1]]) as usize;
+        if len < 4 || len > objects.len() {
+            break;
+        }
+        // Class 1: MPLS label stack, C-Type 1: incoming stack
+        if objects[2] == 1 && objects[3] == 1 {
+            for entry in objects[4..len].chunks_exact(4) {
+                let entry = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]);
+                labels.push(MplsLabel {
+                    label: entry >> 12,
+                    exp: (entry >> 9) as u8 & 0x7,
+                    bottom: entry & 0x100 != 0,
+                    ttl: entry as u8,
+                });
+            }
+        }
+        objects = &objects[len..];
+    }
+    labels
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    // Packets per second
+    pub pps: u32,
+    // Packets sent back to back after an idle period
+    pub burst: u32,
+}
+
+impl RateLimit {
+    // 0 packets per second would hold the queued requests forever
+    pub fn is_valid(&self) -> bool {
+        self.pps > 0
+    }
+}
+
+// Packets through the sockets of a pinger
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Throughput {
+    pub sent: u64,
+    pub received: u64,
+    // Since the pinger was created
+    pub elapsed: Duration,
+}
+
+impl Throughput {
+    pub fn sent_pps(&self) -> f64 {
+        self.sent as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn received_pps(&self) -> f64 {
+        self.received as f64 / self.elapsed.as_secs_f64()
+    }
+
+    // Throughput between an earlier snapshot and this one
+    pub fn since(&self, earlier: &Throughput) -> Throughput {
+        Throughput {
+            sent: self.sent - earlier.sent,
+            received: self.received - earlier.received,
+            elapsed: self.elapsed - earlier.elapsed,
+        }
+    }
+}
+
+// Upper bounds of the buckets of RttHistogram, before the one for longer round trips
+pub const RTT_BUCKETS: [Duration; 12] = [
+    Duration::from_millis(1),
+    Duration::from_millis(2),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(20),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(200),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(5),
+];
+
+// Round trip times of the echo replies of a pinger
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RttHistogram {
+    // Replies up to each bound of RTT_BUCKETS and above the previous one, then above the
+    // last bound
+    pub counts: [u64; RTT_BUCKETS.len() + 1],
+}
+
+impl RttHistogram {
+    // (upper bound, count) of every bucket. None bounds the last one.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        RTT_BUCKETS
+            .iter()
+            .copied()
+            .map(Some)
+            .chain(std::iter::once(None))
+            .zip(self.counts.iter().copied())
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    // Upper bound of the bucket holding the `q` quantile, None for the last bucket or
+    // without any reply
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let total = self.total();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0., 1.) * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bound, count) in self.buckets() {
+            seen += count;
+            if seen >= rank {
+                return bound;
+            }
+        }
+        None
+    }
+}
+
+// What a pinger did since it was created
+#[derive(Debug, Clone, PartialEq)]
+pub struct PingerMetrics {
+    pub sent: u64,
+    // Packets received, including the ICMP traffic of other programs
+    pub received: u64,
+    // Echo replies matched to an ongoing request
+    pub matched: u64,
+    // ICMP errors answering an ongoing request, like Time Exceeded
+    pub errors: u64,
+    pub timeouts: u64,
+    pub send_failures: u64,
+    // Echo replies to no request of the pinger, to requests that had already timed out, and
+    // to requests that were already answered
+    pub unmatched: u64,
+    pub late: u64,
+    pub duplicates: u64,
+    // Requests sent and waiting for an answer
+    pub in_flight: u64,
+    // Requests waiting in the command channel of the backend, out of `command_capacity`, and
+    // for the rate limit
+    pub queued: usize,
+    pub command_capacity: usize,
+    // Of the matched echo replies
+    pub rtt: RttHistogram,
+    pub elapsed: Duration,
+}
+
+// Shared by a backend, its listeners and its pingers
+#[derive(Debug)]
+pub struct Counters {
+    start: Instant,
+    sent: AtomicU64,
+    received: AtomicU64,
+    matched: AtomicU64,
+    errors: AtomicU64,
+    timeouts: AtomicU64,
+    send_failures: AtomicU64,
+    unmatched: AtomicU64,
+    late: AtomicU64,
+    duplicates: AtomicU64,
+    in_flight: AtomicU64,
+    paced: AtomicU64,
+    rtt: [AtomicU64; RTT_BUCKETS.len() + 1],
+}
+
+impl Counters {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            matched: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+            send_failures: AtomicU64::new(0),
+            unmatched: AtomicU64::new(0),
+            late: AtomicU64::new(0),
+            duplicates: AtomicU64::new(0),
+            in_flight: AtomicU64::new(0),
+            paced: AtomicU64::new(0),
+            rtt: Default::default(),
+        }
+    }
+
+    fn sent(&self, count: usize) {
+        self.sent.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    fn received(&self, count: usize) {
+        self.received.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    fn matched(&self, rtt: Duration) {
+        self.matched.fetch_add(1, Ordering::Relaxed);
+        let bucket = RTT_BUCKETS.iter().take_while(|bound| rtt > **bound).count();
+        self.rtt[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn timeout(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn send_failure(&self, count: usize) {
+        self.send_failures
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    fn unmatched(&self) {
+        self.unmatched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn late(&self) {
+        self.late.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn duplicate(&self) {
+        self.duplicates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn set_in_flight(&self, count: usize) {
+        self.in_flight.store(count as u64, Ordering::Relaxed);
+    }
+
+    fn set_paced(&self, count: usize) {
+        self.paced.store(count as u64, Ordering::Relaxed);
+    }
+
+    // The pingers know the occupancy of their command channel
+    fn metrics(&self, queued: usize, command_capacity: usize) -> PingerMetrics {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut rtt = RttHistogram::default();
+        for (count, counter) in rtt.counts.iter_mut().zip(self.rtt.iter()) {
+            *count = load(counter);
+        }
+        PingerMetrics {
+            sent: load(&self.sent),
+            received: load(&self.received),
+            matched: load(&self.matched),
+            errors: load(&self.errors),
+            timeouts: load(&self.timeouts),
+            send_failures: load(&self.send_failures),
+            unmatched: load(&self.unmatched),
+            late: load(&self.late),
+            duplicates: load(&self.duplicates),
+            in_flight: load(&self.in_flight),
+            queued: queued + load(&self.paced) as usize,
+            command_capacity,
+            rtt,
+            elapsed: self.start.elapsed(),
+        }
+    }
+
+    fn throughput(&self) -> Throughput {
+        Throughput {
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            elapsed: self.start.elapsed(),
+        }
+    }
+}
+
+// How the payload of an echo reply differs from the request's
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EchoError {
+    // Only the first `echoed` bytes came back
+    Truncated { sent: usize, echoed: usize },
+    // First byte that differs
+    Corrupted { offset: usize },
 }
 
 #[derive(Debug, Clone)]
 pub enum PingError {
-    Timeout,
+    // Echo identifier and sequence number of the request, for ICMP pingers. Datagram sockets
+    // leave the identifier to the kernel, and report 0 instead.
+    Timeout {
+        echo: Option<(u16, u16)>,
+    },
     TimeExceeded {
-        addr: Ipv4Addr,
+        addr: IpAddr,
         latency: Duration,
+        ip: IpHeaders,
+        // Label stack of the MPLS tunnel the probe expired in, outermost first. Only
+        // available from pingers receiving whole ICMP messages.
+        mpls: Vec<MplsLabel>,
     },
     FailedToSendPacket,
     BackendClosed,
+    // Every probe identifier towards the destination is used by an ongoing request
+    NoFreeIdentifier,
+    // For IPv6 responders, `code` and `ty` hold the raw ICMPv6 values
     IcmpError {
-        responder: Ipv4Addr,
+        responder: IpAddr,
         code: IcmpCode,
         ty: IcmpType,
         data: Box<[u8]>,
         latency: Duration,
+        ip: IpHeaders,
+    },
+    // Destination Unreachable, Packet Too Big or unrecognized next header. Hosts that do not
+    // exist time out instead, so these tell filtered or unrouted targets apart.
+    Unreachable {
+        responder: IpAddr,
+        code: UnreachableCode,
+        quoted: QuotedPacket,
+        latency: Duration,
+        ip: IpHeaders,
+    },
+    // The echo reply did not carry the payload of the request
+    InvalidEcho {
+        reply: Box<PingReply>,
+        error: EchoError,
+    },
+    // Rate limit of 0 packets per second
+    InvalidRateLimit,
+}
+
+impl PingError {
+    // Error for an ICMP(v6) error message answering a probe. `header` tells if the message
+    // quotes the IP header of the probe.
+    fn from_icmp(
+        responder: IpAddr,
+        ty: IcmpType,
+        code: IcmpCode,
+        data: Box<[u8]>,
+        header: bool,
+        latency: Duration,
+        ip: IpHeaders,
+    ) -> Self {
+        let icmpv6 = responder.is_ipv6();
+        match UnreachableCode::from_icmp(icmpv6, ty.0, code.0) {
+            Some(code) => Self::Unreachable {
+                responder,
+                code,
+                quoted: QuotedPacket::parse(icmpv6, &data, header, code),
+                latency,
+                ip,
+            },
+            None => Self::IcmpError {
+                responder,
+                code,
+                ty,
+                data,
+                latency,
+                ip,
+            },
+        }
+    }
+}
+
+// What a pinger saw besides the answers to its requests
+#[derive(Debug, Clone)]
+pub enum Diagnostic {
+    // Reading from a socket failed. Requests time out while this lasts.
+    SocketError {
+        kind: std::io::ErrorKind,
+        message: String,
+    },
+    // ICMP message too short for its type, with the bytes received
+    Unparseable {
+        source: IpAddr,
+        data: Box<[u8]>,
+    },
+    // Echo reply to no request of the pinger, like the replies of other programs
+    UnmatchedReply {
+        responder: IpAddr,
+        id: u16,
+        seq: u16,
+    },
+    // First echo reply to a request that had already timed out
+    LateReply {
+        responder: IpAddr,
+        id: u16,
+        seq: u16,
+        // Time between the timeout and the reply
+        late_by: Duration,
+        // Since the request was sent
+        rtt: Duration,
     },
+    // Another echo reply to a request that was already answered
+    DuplicateReply {
+        responder: IpAddr,
+        id: u16,
+        seq: u16,
+        // Since the request was sent
+        rtt: Duration,
+    },
+}
+
+fn socket_error(e: &std::io::Error) -> Diagnostic {
+    Diagnostic::SocketError {
+        kind: e.kind(),
+        message: e.to_string(),
+    }
+}
+
+fn unparseable(source: IpAddr, data: &[u8]) -> Diagnostic {
+    Diagnostic::Unparseable {
+        source,
+        data: data.into(),
+    }
+}
+
+// Timeouts of the ongoing requests of a backend, earliest first. Entries stay after their
+// request is answered: the backend checks the request is still there when they expire.
+struct Deadlines<K> {
+    heap: BinaryHeap<Reverse<(Instant, K)>>,
+}
+
+impl<K: Ord> Deadlines<K> {
+    fn new() -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    fn push(&mut self, stop: Instant, key: K) {
+        self.heap.push(Reverse((stop, key)));
+    }
+
+    fn next(&self) -> Option<Instant> {
+        self.heap.peek().map(|Reverse((stop, _))| *stop)
+    }
+
+    fn pop_expired(&mut self, now: Instant) -> Option<K> {
+        if self.next()? > now {
+            return None;
+        }
+        self.heap.pop().map(|Reverse((_, key))| key)
+    }
+}
+
+// Token bucket holding back the requests of a backend above its rate limit
+struct Pacer<T> {
+    limit: Option<RateLimit>,
+    tokens: f64,
+    last: Instant,
+    queue: VecDeque<T>,
+}
+
+impl<T> Pacer<T> {
+    fn new(limit: Option<RateLimit>) -> Self {
+        Self {
+            limit,
+            tokens: limit.map(|limit| limit.burst.max(1) as f64).unwrap_or(0.0),
+            last: Instant::now(),
+            queue: VecDeque::new(),
+        }
+    }
+
+    fn set_limit(&mut self, limit: Option<RateLimit>) {
+        self.refill(Instant::now());
+        // The burst is available at once, as with a pacer created with the limit
+        if self.limit.is_none() {
+            self.tokens = limit.map_or(0.0, |limit| limit.burst.max(1) as f64);
+        }
+        self.limit = limit;
+        self.refill(self.last);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(limit) = self.limit {
+            let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * limit.pps as f64).min(limit.burst.max(1) as f64);
+        }
+        self.last = now;
+    }
+
+    fn push(&mut self, request: T) {
+        self.queue.push_back(request);
+    }
+
+    // Next queued request, if the rate limit allows sending it now
+    fn pop(&mut self, now: Instant) -> Option<T> {
+        if self.queue.is_empty() || !self.take(now) {
+            return None;
+        }
+        self.queue.pop_front()
+    }
+
+    // Spend a token, if the rate limit allows it now, without going through the queue
+    fn take(&mut self, now: Instant) -> bool {
+        if self.limit.is_some() {
+            self.refill(now);
+            if self.tokens < 1.0 {
+                return false;
+            }
+            self.tokens -= 1.0;
+        }
+        true
+    }
+
+    // When the next queued request can be sent
+    fn next(&self) -> Option<Instant> {
+        if self.queue.is_empty() {
+            return None;
+        }
+        match self.limit {
+            Some(limit) if self.tokens < 1.0 => {
+                let missing = (1.0 - self.tokens) / limit.pps.max(1) as f64;
+                Some(self.last + Duration::from_nanos((missing * 1e9).ceil() as u64))
+            }
+            _ => Some(self.last),
+        }
+    }
+}
+
+// splitmix64
+struct Random {
+    state: u64,
+}
+
+impl Random {
+    fn new() -> Self {
+        Self::from_seed(RandomState::new().build_hasher().finish())
+    }
+
+    fn from_seed(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+// Next command or reply of a backend, or `timeout` once `wake` is reached. None once every
+// pinger is gone.
+async fn next_input<T>(
+    command_rx: &mut mpsc::Receiver<T>,
+    reply_rx: &mut mpsc::Receiver<T>,
+    wake: Option<Instant>,
+    timeout: T,
+) -> Option<T> {
+    let input = async {
+        tokio::select! {
+            input = command_rx.recv() => input,
+            Some(input) = reply_rx.recv() => Some(input),
+        }
+    };
+    match wake {
+        Some(stop) => {
+            tokio::select! {
+                input = input => input,
+                _ = tokio::time::sleep_until(stop.into()) => Some(timeout),
+            }
+        }
+        None => input.await,
+    }
+}
+
+// Command already waiting in the channel. None when there is none or the channel is closed.
+async fn queued_input<T>(command_rx: &mut mpsc::Receiver<T>) -> Option<T> {
+    std::future::poll_fn(|cx| match command_rx.poll_recv(cx) {
+        Poll::Ready(input) => Poll::Ready(input),
+        Poll::Pending => Poll::Ready(None),
+    })
+    .await
+}
+
+// Next batch of packets, once the socket is readable. None when the runtime shuts down.
+async fn receive_batch(
+    rx: &AsyncFd<Arc<Socket>>,
+    batch: &mut socket::RecvBatch,
+    diagnostics: &broadcast::Sender<Diagnostic>,
+) -> Option<usize> {
+    loop {
+        let mut guard = rx.readable().await.ok()?;
+        match guard.try_io(|rx| rx.get_ref().recv_batch(batch)) {
+            Ok(Ok(count)) => return Some(count),
+            // Already handled as the ICMP message itself
+            Ok(Err(e)) if icmp_errno(&e) => continue,
+            Ok(Err(e)) => {
+                let _ = diagnostics.send(socket_error(&e));
+            }
+            Err(_) => continue,
+        }
+    }
+}
+
+// The ICMP errors about the packets of a socket also fail its next read, with the errno
+// they map to. Datagram sockets get that for every error they queue, raw sockets for the
+// fatal ones.
+fn icmp_errno(e: &std::io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::ENETUNREACH)
+            | Some(libc::EHOSTUNREACH)
+            | Some(libc::EHOSTDOWN)
+            | Some(libc::ENONET)
+            | Some(libc::ENOPROTOOPT)
+            | Some(libc::ECONNREFUSED)
+            | Some(libc::EMSGSIZE)
+            | Some(libc::EOPNOTSUPP)
+            | Some(libc::EPROTO)
+            | Some(libc::EACCES)
+    )
+}
+
+fn ones_complement_add(a: u16, b: u16) -> u16 {
+    let sum = a as u32 + b as u32;
+    ((sum & 0xFF_FF) + (sum >> 16)) as u16
+}
+
+// Raw IPv4 socket getting a copy of the `protocol` packets the host receives, IP header
+// included
+fn raw_ipv4_listener(protocol: libc::c_int) -> Result<AsyncFd<Arc<Socket>>, std::io::Error> {
+    let socket = Socket::new(libc::AF_INET, libc::SOCK_RAW, protocol)?;
+    let _ = socket.set_receive_buffer(RECEIVE_BUFFER_SIZE);
+    AsyncFd::with_interest(Arc::new(socket), Interest::READABLE)
+}
+
+// TCP and UDP checksums cover the source address, which the kernel only picks when routing
+// the packet. Ask it which one it would use.
+fn source_address(destination: Ipv4Addr, port: u16) -> Result<Ipv4Addr, std::io::Error> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect((destination, port))?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(addr) => Ok(addr),
+        IpAddr::V6(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "IPv6 source for an IPv4 destination",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rtt_quantiles() {
+        let mut histogram = RttHistogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+        // 1ms, 3ms, 3ms and 10s
+        histogram.counts[0] = 1;
+        histogram.counts[2] = 2;
+        histogram.counts[RTT_BUCKETS.len()] = 1;
+        assert_eq!(histogram.quantile(0.), Some(Duration::from_millis(1)));
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_millis(5)));
+        assert_eq!(histogram.quantile(0.75), Some(Duration::from_millis(5)));
+        assert_eq!(histogram.quantile(0.9), None);
+    }
+
+    #[test]
+    fn pacer_limits_rate() {
+        let mut pacer = Pacer::new(Some(RateLimit { pps: 10, burst: 2 }));
+        let start = pacer.last;
+        for i in 0..4 {
+            pacer.push(i);
+        }
+        assert_eq!(pacer.pop(start), Some(0));
+        assert_eq!(pacer.pop(start), Some(1));
+        assert_eq!(pacer.pop(start), None);
+        let next = pacer.next().unwrap();
+        assert_eq!(next - start, Duration::from_millis(100));
+        assert_eq!(pacer.pop(next), Some(2));
+        assert_eq!(pacer.pop(next), None);
+
+        pacer.set_limit(None);
+        assert_eq!(pacer.pop(next), Some(3));
+        assert_eq!(pacer.next(), None);
+
+        pacer.set_limit(Some(RateLimit { pps: 10, burst: 2 }));
+        for i in 4..7 {
+            pacer.push(i);
+        }
+        let now = pacer.last;
+        assert_eq!(pacer.pop(now), Some(4));
+        assert_eq!(pacer.pop(now), Some(5));
+        assert_eq!(pacer.pop(now), None);
+    }
+
+    #[test]
+    fn mpls_extensions_are_parsed() {
+        // Label 24001, exp 5, bottom of stack, TTL 1
+        let entry = (24001u32 << 12) | (5 << 9) | 0x100 | 1;
+        let mut extensions = vec![0x20, 0, 0, 0, 0, 8, 1, 1];
+        extensions.extend_from_slice(&entry.to_be_bytes());
+        let checksum = pnet::util::checksum(&extensions, 1);
+        extensions[2..4].copy_from_slice(&checksum.to_be_bytes());
+        let expected = vec![MplsLabel {
+            label: 24001,
+            exp: 5,
+            bottom: true,
+            ttl: 1,
+        }];
+
+        // Time exceeded quoting 128 bytes, with the RFC 4884 length
+        let mut message = vec![11, 0, 0, 0, 0, 32, 0, 0];
+        message.extend_from_slice(&[0; 128]);
+        message.extend_from_slice(&extensions);
+        assert_eq!(mpls_labels(false, &message), expected);
+        // Without it
+        message[5] = 0;
+        assert_eq!(mpls_labels(false, &message), expected);
+        message[4] = 16;
+        assert_eq!(mpls_labels(true, &message), expected);
+
+        // Bad checksum
+        let last = message.len() - 1;
+        message[last] ^= 1;
+        assert_eq!(mpls_labels(true, &message), vec![]);
+        // No extensions
+        assert_eq!(mpls_labels(false, &message[..100]), vec![]);
+    }
 }
@@ -0,0 +1,455 @@
+// In-memory network for tests, reached through the Transport of ICMP pingers
+use super::icmp::{Pinger, PingerOptions};
+use super::transport::{Incoming, PacketOptions, Transport};
+use super::{Pacer, Random, RateLimit};
+use pnet::packet::icmp::echo_request::EchoRequestPacket;
+use pnet::packet::icmpv6::{self, Icmpv6Packet};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet::packet::Packet;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+// Addresses of the host the pingers run on
+pub const LOCAL_ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 100);
+pub const LOCAL_ADDRESS6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x100);
+
+// Packets waiting for a simulated pinger to read them
+const INCOMING_CAPACITY: usize = 1024;
+// TTL of the packets the hops send
+const HOP_TTL: u8 = 64;
+
+// Router, or destination host, of a simulated route
+#[derive(Debug, Clone)]
+pub struct Hop {
+    // None for routers that never send Time Exceeded messages
+    addr: Option<IpAddr>,
+    // One way, from the previous hop. Answers take as long to come back.
+    latency: Duration,
+    // Share of the probes dropped before reaching the hop
+    loss: f64,
+    // Share of the probes the hop passes on, or answers, twice
+    duplicate: f64,
+    // ICMP messages sent by the hop. Probes above the limit get no answer.
+    rate_limit: Option<RateLimit>,
+}
+
+impl Hop {
+    pub fn new(addr: IpAddr, latency: Duration) -> Self {
+        Self {
+            addr: Some(addr),
+            latency,
+            loss: 0.,
+            duplicate: 0.,
+            rate_limit: None,
+        }
+    }
+
+    pub fn silent(latency: Duration) -> Self {
+        Self {
+            addr: None,
+            latency,
+            loss: 0.,
+            duplicate: 0.,
+            rate_limit: None,
+        }
+    }
+
+    // Between 0 and 1
+    pub fn loss(mut self, loss: f64) -> Self {
+        self.loss = loss;
+        self
+    }
+
+    // Between 0 and 1
+    pub fn duplicate(mut self, duplicate: f64) -> Self {
+        self.duplicate = duplicate;
+        self
+    }
+
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+}
+
+struct Node {
+    hop: Hop,
+    pacer: Pacer<()>,
+}
+
+struct Route {
+    destination: usize,
+    // Nodes before the destination
+    paths: Vec<Vec<usize>>,
+}
+
+struct Network {
+    nodes: Vec<Node>,
+    routes: HashMap<IpAddr, Route>,
+    random: Random,
+}
+
+// Probe as it leaves the host
+struct Probe {
+    source: IpAddr,
+    destination: IpAddr,
+    ttl: u8,
+    tos: u8,
+    // ICMP(v6) echo request
+    message: Vec<u8>,
+}
+
+impl Probe {
+    // Probe a pinger sends to `addr`
+    fn parse(packet: &[u8], addr: IpAddr, options: &PacketOptions) -> Option<Self> {
+        match addr {
+            // Pingers with `ip_header` send whole IPv4 packets
+            IpAddr::V4(_) if packet.first()? >> 4 == 4 => Self::from_ip(packet),
+            IpAddr::V4(_) => Self::new(
+                IpAddr::V4(LOCAL_ADDRESS),
+                addr,
+                options.ttl.unwrap_or(HOP_TTL),
+                options.tos.unwrap_or(0),
+                packet.to_vec(),
+            ),
+            IpAddr::V6(_) => Self::new(
+                IpAddr::V6(LOCAL_ADDRESS6),
+                addr,
+                options.ttl.unwrap_or(HOP_TTL),
+                options.tos.unwrap_or(0),
+                packet.to_vec(),
+            ),
+        }
+    }
+
+    // Probe in an IPv4 or IPv6 packet
+    fn from_ip(packet: &[u8]) -> Option<Self> {
+        match packet.first()? >> 4 {
+            4 => {
+                let packet = Ipv4Packet::new(packet)?;
+                let source = match packet.get_source() {
+                    // Filled in by the kernel
+                    source if source.is_unspecified() => LOCAL_ADDRESS,
+                    source => source,
+                };
+                Self::new(
+                    IpAddr::V4(source),
+                    IpAddr::V4(packet.get_destination()),
+                    packet.get_ttl(),
+                    (packet.get_dscp() << 2) | packet.get_ecn(),
+                    packet.payload().to_vec(),
+                )
+            }
+            6 => {
+                let packet = Ipv6Packet::new(packet)?;
+                if packet.get_next_header() != IpNextHeaderProtocols::Icmpv6 {
+                    return None;
+                }
+                Self::new(
+                    IpAddr::V6(packet.get_source()),
+                    IpAddr::V6(packet.get_destination()),
+                    packet.get_hop_limit(),
+                    packet.get_traffic_class(),
+                    packet.payload().to_vec(),
+                )
+            }
+            _ => None,
+        }
+    }
+
+    // Only echo requests get an answer
+    fn new(
+        source: IpAddr,
+        destination: IpAddr,
+        ttl: u8,
+        tos: u8,
+        message: Vec<u8>,
+    ) -> Option<Self> {
+        let echo_request = if destination.is_ipv4() {
+            8
+        } else {
+            icmpv6::Icmpv6Types::EchoRequest.0
+        };
+        if EchoRequestPacket::new(&message)?.get_icmp_type().0 != echo_request {
+            return None;
+        }
+        Some(Self {
+            source,
+            destination,
+            ttl,
+            tos,
+            message,
+        })
+    }
+
+    fn checksum(&self) -> u16 {
+        u16::from_be_bytes([self.message[2], self.message[3]])
+    }
+
+    // The probe as it reached a hop, with the TTL it had left
+    fn quote(&self, ttl: u8) -> Vec<u8> {
+        match (self.source, self.destination) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                ipv4_packet(source, destination, ttl, self.tos, &self.message)
+            }
+            (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                ipv6_packet(source, destination, ttl, self.tos, &self.message)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // Time Exceeded message of `responder`, or echo reply of the destination, in the IP
+    // packet that carries it back
+    fn answer(&self, responder: IpAddr, distance: usize, time_exceeded: bool) -> Vec<u8> {
+        let ttl = HOP_TTL.saturating_sub(distance as u8);
+        let mut message = if time_exceeded {
+            let mut message = vec![0; 8];
+            message.extend_from_slice(&self.quote(1));
+            message
+        } else {
+            self.message.clone()
+        };
+        match (responder, self.source) {
+            (IpAddr::V4(responder), IpAddr::V4(source)) => {
+                message[0] = if time_exceeded { 11 } else { 0 };
+                set_checksum(&mut message, |message| pnet::util::checksum(message, 1));
+                ipv4_packet(responder, source, ttl, self.tos, &message)
+            }
+            (IpAddr::V6(responder), IpAddr::V6(source)) => {
+                message[0] = if time_exceeded {
+                    icmpv6::Icmpv6Types::TimeExceeded.0
+                } else {
+                    icmpv6::Icmpv6Types::EchoReply.0
+                };
+                set_checksum(&mut message, |message| {
+                    let packet = Icmpv6Packet::new(message).unwrap();
+                    icmpv6::checksum(&packet, &responder, &source)
+                });
+                ipv6_packet(responder, source, ttl, self.tos, &message)
+            }
+            // Routes lead to hosts of the same family
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn set_checksum(message: &mut [u8], checksum: impl Fn(&[u8]) -> u16) {
+    message[2..4].copy_from_slice(&[0, 0]);
+    let checksum = checksum(message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+}
+
+fn ipv4_packet(
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    ttl: u8,
+    tos: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let header_size = Ipv4Packet::minimum_packet_size();
+    let mut vec = vec![0; header_size + payload.len()];
+    let len = vec.len() as u16;
+    let mut packet = MutableIpv4Packet::new(&mut vec).unwrap();
+    packet.set_version(4);
+    packet.set_header_length((header_size / 4) as u8);
+    packet.set_total_length(len);
+    packet.set_dscp(tos >> 2);
+    packet.set_ecn(tos & 3);
+    packet.set_ttl(ttl);
+    packet.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+    packet.set_source(source);
+    packet.set_destination(destination);
+    packet.set_payload(payload);
+    let checksum = ipv4::checksum(&packet.to_immutable());
+    packet.set_checksum(checksum);
+    vec
+}
+
+fn ipv6_packet(
+    source: Ipv6Addr,
+    destination: Ipv6Addr,
+    ttl: u8,
+    tos: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let header_size = Ipv6Packet::minimum_packet_size();
+    let mut vec = vec![0; header_size + payload.len()];
+    let mut packet = MutableIpv6Packet::new(&mut vec).unwrap();
+    packet.set_version(6);
+    packet.set_traffic_class(tos);
+    packet.set_payload_length(payload.len() as u16);
+    packet.set_next_header(IpNextHeaderProtocols::Icmpv6);
+    packet.set_hop_limit(ttl);
+    packet.set_source(source);
+    packet.set_destination(destination);
+    packet.set_payload(payload);
+    vec
+}
+
+impl Network {
+    // Hops sharing an address are the same router
+    fn node(&mut self, hop: Hop) -> usize {
+        let existing = hop.addr.and_then(|addr| {
+            self.nodes
+                .iter()
+                .position(|node| node.hop.addr == Some(addr))
+        });
+        existing.unwrap_or_else(|| {
+            let pacer = Pacer::new(hop.rate_limit);
+            self.nodes.push(Node { hop, pacer });
+            self.nodes.len() - 1
+        })
+    }
+
+    // Answer to a probe, from whom, and how long it takes to come back
+    fn forward(&mut self, probe: &Probe) -> Option<Answer> {
+        let route = self.routes.get(&probe.destination)?;
+        let path = match route.paths.len() {
+            0 => &[][..],
+            count => &route.paths[probe.checksum() as usize % count][..],
+        };
+        let now = Instant::now();
+        let mut delay = Duration::ZERO;
+        let mut copies = 1;
+        for (i, &index) in path.iter().chain(Some(&route.destination)).enumerate() {
+            let node = &mut self.nodes[index];
+            delay += node.hop.latency;
+            if self.random.next_f64() < node.hop.loss {
+                return None;
+            }
+            // Drawn only for duplicating hops, to keep the losses of a seed
+            if node.hop.duplicate > 0. && self.random.next_f64() < node.hop.duplicate {
+                copies *= 2;
+            }
+            let reached = i == path.len();
+            if !reached && (probe.ttl as usize) > i + 1 {
+                continue;
+            }
+            let responder = node.hop.addr?;
+            if !node.pacer.take(now) {
+                return None;
+            }
+            return Some(Answer {
+                delay: delay * 2,
+                responder,
+                packet: probe.answer(responder, i + 1, !reached),
+                copies,
+            });
+        }
+        None
+    }
+}
+
+struct Answer {
+    delay: Duration,
+    responder: IpAddr,
+    // IP packet
+    packet: Vec<u8>,
+    // Identical answers arriving together
+    copies: usize,
+}
+
+// Fixed routes from the local host to the destinations of the probes. Probes expire at the
+// hop their TTL runs out at, and to other destinations they get lost.
+#[derive(Clone)]
+pub struct SimulatedNetwork {
+    network: Arc<Mutex<Network>>,
+}
+
+impl SimulatedNetwork {
+    // Losses are drawn from `seed`, so that tests see the same ones on every run
+    pub fn new(seed: u64) -> Self {
+        Self {
+            network: Arc::new(Mutex::new(Network {
+                nodes: vec![],
+                routes: HashMap::new(),
+                random: Random::from_seed(seed),
+            })),
+        }
+    }
+
+    // Route to the address of `destination` through one of `paths`, the routers in between.
+    // Probes pick the path at their ICMP checksum modulo the number of paths, the way per flow
+    // load balancers hash ICMP messages.
+    pub fn route(self, destination: Hop, paths: Vec<Vec<Hop>>) -> Self {
+        {
+            let mut network = self.network.lock().unwrap();
+            let addr = destination
+                .addr
+                .expect("Destinations answer from their address");
+            let destination = network.node(destination);
+            let paths = paths
+                .into_iter()
+                .map(|path| path.into_iter().map(|hop| network.node(hop)).collect())
+                .collect();
+            network.routes.insert(addr, Route { destination, paths });
+        }
+        self
+    }
+
+    // Answer to an IPv4 or IPv6 packet, how long it takes to come back, and how many copies
+    // of it do, for emulators handing the network real packets
+    pub fn answer(&self, packet: &[u8]) -> Option<(Duration, Vec<u8>, usize)> {
+        let probe = Probe::from_ip(packet)?;
+        let answer = self.network.lock().unwrap().forward(&probe)?;
+        Some((answer.delay, answer.packet, answer.copies))
+    }
+
+    // Pinger on the local host. Needs a Tokio runtime.
+    pub fn pinger(&self, options: PingerOptions) -> Pinger {
+        let (incoming_tx, incoming) = mpsc::channel(INCOMING_CAPACITY);
+        let transport = SimulatedTransport {
+            network: self.network.clone(),
+            incoming: incoming_tx,
+        };
+        Pinger::with_transport(options, Arc::new(transport), incoming)
+    }
+}
+
+struct SimulatedTransport {
+    network: Arc<Mutex<Network>>,
+    incoming: mpsc::Sender<Incoming>,
+}
+
+impl Transport for SimulatedTransport {
+    fn send_to(
+        &self,
+        packet: &[u8],
+        addr: IpAddr,
+        options: &PacketOptions,
+    ) -> Result<usize, std::io::Error> {
+        let answer = Probe::parse(packet, addr, options)
+            .and_then(|probe| self.network.lock().unwrap().forward(&probe));
+        if let Some(Answer {
+            delay,
+            responder,
+            mut packet,
+            copies,
+        }) = answer
+        {
+            // Raw sockets only give the ICMPv6 message
+            if responder.is_ipv6() {
+                packet.drain(..Ipv6Packet::minimum_packet_size());
+            }
+            let answer = Incoming {
+                source: responder,
+                data: packet.into(),
+                timestamp: None,
+            };
+            let incoming = self.incoming.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                for _ in 0..copies {
+                    let _ = incoming.send(answer.clone()).await;
+                }
+            });
+        }
+        Ok(packet.len())
+    }
+}
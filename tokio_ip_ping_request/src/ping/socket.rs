@@ -0,0 +1,646 @@
+// Socket calls pnet does not expose
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// From linux/net_tstamp.h and linux/errqueue.h. libc only has some of them.
+const SO_TIMESTAMPNS: libc::c_int = 35;
+const SO_TIMESTAMPING: libc::c_int = 37;
+const SOF_TIMESTAMPING_TX_SOFTWARE: libc::c_uint = 1 << 1;
+const SOF_TIMESTAMPING_RX_SOFTWARE: libc::c_uint = 1 << 3;
+const SOF_TIMESTAMPING_SOFTWARE: libc::c_uint = 1 << 4;
+const SOF_TIMESTAMPING_OPT_TSONLY: libc::c_uint = 1 << 11;
+const SO_EE_ORIGIN_ICMP: u8 = 2;
+const SO_EE_ORIGIN_ICMP6: u8 = 3;
+
+// Room for a timestamping control message and an extended error
+const CONTROL_SIZE: usize = 256;
+// Room for the control messages of PacketOptions, in words to keep them aligned
+const SEND_CONTROL_WORDS: usize = 16;
+const IP_PMTUDISC_DONT: libc::c_int = 0;
+const IP_PMTUDISC_WANT: libc::c_int = 1;
+const IP_PMTUDISC_PROBE: libc::c_int = 3;
+const IPV6_DONTFRAG: libc::c_int = 62;
+
+// IP header fields set per packet, through control messages. None keeps the socket's.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PacketOptions {
+    pub ttl: Option<u8>,
+    // Type of service, or IPv6 traffic class: DSCP and ECN
+    pub tos: Option<u8>,
+    // IPv6 only. IPv4 sockets need set_dont_fragment.
+    pub dont_fragment: Option<bool>,
+}
+
+type SendControl = [u64; SEND_CONTROL_WORDS];
+
+// Fill `control` with the messages for `options`, and return their size
+fn write_control(addr: IpAddr, options: &PacketOptions, control: &mut SendControl) -> usize {
+    let (level, messages) = match addr {
+        IpAddr::V4(_) => (
+            libc::IPPROTO_IP,
+            [
+                (libc::IP_TTL, options.ttl.map(libc::c_int::from)),
+                (libc::IP_TOS, options.tos.map(libc::c_int::from)),
+                // No control message for the DF bit
+                (0, None),
+            ],
+        ),
+        IpAddr::V6(_) => (
+            libc::IPPROTO_IPV6,
+            [
+                (libc::IPV6_HOPLIMIT, options.ttl.map(libc::c_int::from)),
+                (libc::IPV6_TCLASS, options.tos.map(libc::c_int::from)),
+                (IPV6_DONTFRAG, options.dont_fragment.map(libc::c_int::from)),
+            ],
+        ),
+    };
+    let base = control.as_mut_ptr() as *mut u8;
+    let mut len = 0;
+    for (ty, value) in messages.iter() {
+        let value = match value {
+            Some(value) => *value,
+            None => continue,
+        };
+        unsafe {
+            let header = base.add(len) as *mut libc::cmsghdr;
+            (*header).cmsg_len = libc::CMSG_LEN(mem::size_of::<libc::c_int>() as u32) as _;
+            (*header).cmsg_level = level;
+            (*header).cmsg_type = *ty;
+            std::ptr::write_unaligned(libc::CMSG_DATA(header) as *mut libc::c_int, value);
+            len += libc::CMSG_SPACE(mem::size_of::<libc::c_int>() as u32) as usize;
+        }
+    }
+    len
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timestamping {
+    None,
+    // Received packets only
+    Receive,
+    // Sent packets are reported on the error queue
+    SendAndReceive,
+}
+
+#[derive(Debug)]
+pub struct Received {
+    pub len: usize,
+    pub addr: IpAddr,
+    // Kernel reception time, if timestamps are enabled
+    pub timestamp: Option<SystemTime>,
+}
+
+// ICMP error queued for a datagram socket, with IP_RECVERR
+#[derive(Debug)]
+pub struct ErrorReport {
+    // Size of the probe the error is about, copied in the receive buffer
+    pub len: usize,
+    // Destination of the probe
+    pub destination: IpAddr,
+    // Host that sent the ICMP error
+    pub offender: IpAddr,
+    pub icmpv6: bool,
+    pub ty: u8,
+    pub code: u8,
+    // Type specific data, like the next-hop MTU
+    pub info: u32,
+    pub timestamp: Option<SystemTime>,
+}
+
+struct Message {
+    len: usize,
+    addr: Option<IpAddr>,
+    timestamp: Option<SystemTime>,
+    error: Option<(libc::sock_extended_err, Option<IpAddr>)>,
+}
+
+// Buffers of sendmmsg
+pub struct SendBatch {
+    addrs: Vec<(libc::sockaddr_storage, libc::socklen_t)>,
+    controls: Vec<(SendControl, usize)>,
+    iovecs: Vec<libc::iovec>,
+    headers: Vec<libc::mmsghdr>,
+}
+
+// The pointers in the headers are only used during `send_batch`, which sets them
+unsafe impl Send for SendBatch {}
+
+impl SendBatch {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            addrs: Vec::with_capacity(capacity),
+            controls: Vec::with_capacity(capacity),
+            iovecs: Vec::with_capacity(capacity),
+            headers: Vec::with_capacity(capacity),
+        }
+    }
+}
+
+// Buffers of recvmmsg, kept between batches
+pub struct RecvBatch {
+    buffer_size: usize,
+    buffers: Vec<u8>,
+    controls: Vec<u8>,
+    addrs: Vec<libc::sockaddr_storage>,
+    iovecs: Vec<libc::iovec>,
+    headers: Vec<libc::mmsghdr>,
+    // Messages of the last batch. None for unknown address families.
+    received: Vec<Option<Received>>,
+}
+
+// The pointers in the headers are only used during `recv_batch`, which sets them
+unsafe impl Send for RecvBatch {}
+
+impl RecvBatch {
+    pub fn new(capacity: usize, buffer_size: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            buffer_size,
+            buffers: vec![0; capacity * buffer_size],
+            controls: vec![0; capacity * CONTROL_SIZE],
+            addrs: vec![unsafe { mem::zeroed() }; capacity],
+            iovecs: Vec::with_capacity(capacity),
+            headers: Vec::with_capacity(capacity),
+            received: Vec::with_capacity(capacity),
+        }
+    }
+
+    // Message `i` of the last batch, with its data
+    pub fn get(&self, i: usize) -> Option<(&Received, &[u8])> {
+        let received = self.received.get(i)?.as_ref()?;
+        let start = i * self.buffer_size;
+        Some((received, &self.buffers[start..start + received.len]))
+    }
+}
+
+fn to_system_time(ts: &libc::timespec) -> Option<SystemTime> {
+    if ts.tv_sec == 0 && ts.tv_nsec == 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+fn to_ip_addr(addr: &libc::sockaddr_storage) -> Option<IpAddr> {
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                addr.sin_addr.s_addr,
+            ))))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+            Some(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)))
+        }
+        _ => None,
+    }
+}
+
+fn to_sockaddr(addr: IpAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        IpAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_addr.s_addr = u32::from(addr).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        IpAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_addr.s6_addr = addr.octets();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+// Timestamp and extended error found in the control messages of a received message
+unsafe fn parse_control(msg: &libc::msghdr, message: &mut Message) {
+    let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+    while !cmsg.is_null() {
+        let level = (*cmsg).cmsg_level;
+        let ty = (*cmsg).cmsg_type;
+        let data = libc::CMSG_DATA(cmsg);
+        if level == libc::SOL_SOCKET && ty == SO_TIMESTAMPNS {
+            let ts = std::ptr::read_unaligned(data as *const libc::timespec);
+            message.timestamp = to_system_time(&ts);
+        } else if level == libc::SOL_SOCKET && ty == SO_TIMESTAMPING {
+            // Software, deprecated and hardware timestamps
+            let ts = std::ptr::read_unaligned(data as *const [libc::timespec; 3]);
+            message.timestamp = to_system_time(&ts[0]);
+        } else if (level == libc::SOL_IP && ty == libc::IP_RECVERR)
+            || (level == libc::SOL_IPV6 && ty == libc::IPV6_RECVERR)
+        {
+            let error = std::ptr::read_unaligned(data as *const libc::sock_extended_err);
+            // SO_EE_OFFENDER: the address follows the error
+            let offender = data.add(mem::size_of::<libc::sock_extended_err>());
+            let family = std::ptr::read_unaligned(offender as *const libc::sa_family_t);
+            let offender = if family as libc::c_int == libc::AF_INET {
+                let mut storage: libc::sockaddr_storage = mem::zeroed();
+                std::ptr::copy_nonoverlapping(
+                    offender,
+                    &mut storage as *mut _ as *mut u8,
+                    mem::size_of::<libc::sockaddr_in>(),
+                );
+                to_ip_addr(&storage)
+            } else if family as libc::c_int == libc::AF_INET6 {
+                let mut storage: libc::sockaddr_storage = mem::zeroed();
+                std::ptr::copy_nonoverlapping(
+                    offender,
+                    &mut storage as *mut _ as *mut u8,
+                    mem::size_of::<libc::sockaddr_in6>(),
+                );
+                to_ip_addr(&storage)
+            } else {
+                None
+            };
+            message.error = Some((error, offender));
+        }
+        cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+    }
+}
+
+fn io_error(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+// Owned socket file descriptor
+pub struct Socket {
+    fd: libc::c_int,
+    // Kept between the batches, which grow them to their size
+    send_buffers: Mutex<SendBatch>,
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+impl Socket {
+    pub fn new(
+        domain: libc::c_int,
+        ty: libc::c_int,
+        protocol: libc::c_int,
+    ) -> Result<Self, std::io::Error> {
+        let fd = unsafe { libc::socket(domain, ty | libc::SOCK_CLOEXEC, protocol) };
+        if fd == -1 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(Self {
+                fd,
+                send_buffers: Mutex::new(SendBatch::new(0)),
+            })
+        }
+    }
+
+    fn setsockopt<T>(
+        &self,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: &T,
+    ) -> Result<(), std::io::Error> {
+        let res = unsafe {
+            libc::setsockopt(
+                self.fd,
+                level,
+                name,
+                value as *const T as *const libc::c_void,
+                mem::size_of::<T>() as libc::socklen_t,
+            )
+        };
+        if res == -1 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    // IPv4 has no control message for the DF bit. With Some(true), packets are sent
+    // whatever the path MTU the kernel knows of, and None lets it discover the path MTU.
+    pub fn set_dont_fragment(&self, dont_fragment: Option<bool>) -> Result<(), std::io::Error> {
+        let mode = match dont_fragment {
+            Some(true) => IP_PMTUDISC_PROBE,
+            Some(false) => IP_PMTUDISC_DONT,
+            None => IP_PMTUDISC_WANT,
+        };
+        self.setsockopt(libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, &mode)
+    }
+
+    // The IPv4 header is built by the caller
+    pub fn set_header_included(&self) -> Result<(), std::io::Error> {
+        let on: libc::c_int = 1;
+        self.setsockopt(libc::IPPROTO_IP, libc::IP_HDRINCL, &on)
+    }
+
+    // Fails when no interface has the address
+    pub fn bind(&self, addr: IpAddr) -> Result<(), std::io::Error> {
+        let (addr, addr_len) = to_sockaddr(addr);
+        let res = unsafe {
+            libc::bind(
+                self.fd,
+                &addr as *const _ as *const libc::sockaddr,
+                addr_len,
+            )
+        };
+        if res == -1 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    // Send and receive through the interface only, whatever the routing table says
+    pub fn bind_to_device(&self, device: &str) -> Result<(), std::io::Error> {
+        if device.len() >= libc::IFNAMSIZ || device.contains('\0') {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Invalid interface name",
+            ));
+        }
+        let res = unsafe {
+            libc::setsockopt(
+                self.fd,
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                device.as_ptr() as *const libc::c_void,
+                device.len() as libc::socklen_t,
+            )
+        };
+        if res == -1 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    // Root may go above net.core.rmem_max
+    pub fn set_receive_buffer(&self, size: usize) -> Result<(), std::io::Error> {
+        let size = size as libc::c_int;
+        self.setsockopt(libc::SOL_SOCKET, libc::SO_RCVBUFFORCE, &size)
+            .or_else(|_| self.setsockopt(libc::SOL_SOCKET, libc::SO_RCVBUF, &size))
+    }
+
+    // Queue the ICMP errors about sent packets on the error queue
+    pub fn enable_errors(&self, ipv6: bool) -> Result<(), std::io::Error> {
+        let on: libc::c_int = 1;
+        if ipv6 {
+            self.setsockopt(libc::IPPROTO_IPV6, libc::IPV6_RECVERR, &on)
+        } else {
+            self.setsockopt(libc::IPPROTO_IP, libc::IP_RECVERR, &on)
+        }
+    }
+
+    // Ask the kernel for software timestamps, falling back to receive timestamps only when
+    // SO_TIMESTAMPING is not supported. Transmit timestamps are only wanted when nothing
+    // else reads the error queue.
+    pub fn enable_timestamps(&self, send: bool) -> Timestamping {
+        if send {
+            let flags = SOF_TIMESTAMPING_TX_SOFTWARE
+                | SOF_TIMESTAMPING_RX_SOFTWARE
+                | SOF_TIMESTAMPING_SOFTWARE
+                | SOF_TIMESTAMPING_OPT_TSONLY;
+            if self
+                .setsockopt(libc::SOL_SOCKET, SO_TIMESTAMPING, &flags)
+                .is_ok()
+            {
+                return Timestamping::SendAndReceive;
+            }
+        }
+        let on: libc::c_int = 1;
+        if self
+            .setsockopt(libc::SOL_SOCKET, SO_TIMESTAMPNS, &on)
+            .is_ok()
+        {
+            return Timestamping::Receive;
+        }
+        Timestamping::None
+    }
+
+    pub fn send_to(
+        &self,
+        buf: &[u8],
+        addr: IpAddr,
+        options: &PacketOptions,
+    ) -> Result<usize, std::io::Error> {
+        let mut control: SendControl = [0; SEND_CONTROL_WORDS];
+        let control_len = write_control(addr, options, &mut control);
+        let (mut addr, addr_len) = to_sockaddr(addr);
+        let mut iovec = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = addr_len;
+        msg.msg_iov = &mut iovec;
+        msg.msg_iovlen = 1;
+        if control_len > 0 {
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = control_len as _;
+        }
+        let len = unsafe { libc::sendmsg(self.fd, &msg, 0) };
+        if len == -1 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(len as usize)
+        }
+    }
+
+    fn recvmsg(&self, buf: &mut [u8], flags: libc::c_int) -> Result<Message, std::io::Error> {
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut control = [0u8; CONTROL_SIZE];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = CONTROL_SIZE as _;
+
+        let len = unsafe { libc::recvmsg(self.fd, &mut msg, flags) };
+        if len == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut message = Message {
+            len: len as usize,
+            addr: if msg.msg_namelen > 0 {
+                to_ip_addr(&addr)
+            } else {
+                None
+            },
+            timestamp: None,
+            error: None,
+        };
+        unsafe { parse_control(&msg, &mut message) };
+        Ok(message)
+    }
+
+    // Send as many packets as possible with one system call per batch. Returns how many
+    // were sent, stopping at the first error.
+    pub fn send_batch<'a>(
+        &self,
+        packets: impl IntoIterator<Item = (&'a [u8], IpAddr, PacketOptions)>,
+    ) -> usize {
+        let mut batch = self.send_buffers.lock().unwrap();
+        let batch = &mut *batch;
+        batch.addrs.clear();
+        batch.controls.clear();
+        batch.iovecs.clear();
+        batch.headers.clear();
+        for (packet, addr, options) in packets {
+            let mut control: SendControl = [0; SEND_CONTROL_WORDS];
+            let control_len = write_control(addr, &options, &mut control);
+            batch.controls.push((control, control_len));
+            batch.addrs.push(to_sockaddr(addr));
+            batch.iovecs.push(libc::iovec {
+                iov_base: packet.as_ptr() as *mut libc::c_void,
+                iov_len: packet.len(),
+            });
+        }
+        for (i, (addr, addr_len)) in batch.addrs.iter_mut().enumerate() {
+            let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+            msg.msg_name = addr as *mut _ as *mut libc::c_void;
+            msg.msg_namelen = *addr_len;
+            msg.msg_iov = &mut batch.iovecs[i];
+            msg.msg_iovlen = 1;
+            let (control, control_len) = &mut batch.controls[i];
+            if *control_len > 0 {
+                msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                msg.msg_controllen = *control_len as _;
+            }
+            batch.headers.push(libc::mmsghdr {
+                msg_hdr: msg,
+                msg_len: 0,
+            });
+        }
+
+        let mut sent = 0;
+        while sent < batch.headers.len() {
+            let res = unsafe {
+                libc::sendmmsg(
+                    self.fd,
+                    batch.headers[sent..].as_mut_ptr(),
+                    (batch.headers.len() - sent) as libc::c_uint,
+                    0,
+                )
+            };
+            if res <= 0 {
+                break;
+            }
+            sent += res as usize;
+        }
+        sent
+    }
+
+    // Non blocking read of the queued packets, up to the size of the batch. Raw IPv4
+    // sockets return the IP header with the payload.
+    pub fn recv_batch(&self, batch: &mut RecvBatch) -> Result<usize, std::io::Error> {
+        let count = batch.addrs.len();
+        batch.iovecs.clear();
+        batch.headers.clear();
+        for chunk in batch.buffers.chunks_mut(batch.buffer_size) {
+            batch.iovecs.push(libc::iovec {
+                iov_base: chunk.as_mut_ptr() as *mut libc::c_void,
+                iov_len: chunk.len(),
+            });
+        }
+        let controls = batch.controls.chunks_mut(CONTROL_SIZE);
+        for (i, (addr, control)) in batch.addrs.iter_mut().zip(controls).enumerate() {
+            let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+            msg.msg_name = addr as *mut _ as *mut libc::c_void;
+            msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_iov = &mut batch.iovecs[i];
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = CONTROL_SIZE as _;
+            batch.headers.push(libc::mmsghdr {
+                msg_hdr: msg,
+                msg_len: 0,
+            });
+        }
+
+        let res = unsafe {
+            libc::recvmmsg(
+                self.fd,
+                batch.headers.as_mut_ptr(),
+                count as libc::c_uint,
+                libc::MSG_DONTWAIT,
+                std::ptr::null_mut(),
+            )
+        };
+        if res == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        batch.received.clear();
+        for i in 0..res as usize {
+            let header = &batch.headers[i];
+            let mut message = Message {
+                len: header.msg_len as usize,
+                addr: if header.msg_hdr.msg_namelen > 0 {
+                    to_ip_addr(&batch.addrs[i])
+                } else {
+                    None
+                },
+                timestamp: None,
+                error: None,
+            };
+            unsafe { parse_control(&header.msg_hdr, &mut message) };
+            batch.received.push(message.addr.map(|addr| Received {
+                len: message.len.min(batch.buffer_size),
+                addr,
+                timestamp: message.timestamp,
+            }));
+        }
+        Ok(batch.received.len())
+    }
+
+    // Non blocking read of the next ICMP error on the error queue
+    pub fn recv_error(&self, buf: &mut [u8]) -> Result<ErrorReport, std::io::Error> {
+        let message = self.recvmsg(buf, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT)?;
+        let (error, offender) = message
+            .error
+            .ok_or_else(|| io_error("Missing extended error"))?;
+        if error.ee_origin != SO_EE_ORIGIN_ICMP && error.ee_origin != SO_EE_ORIGIN_ICMP6 {
+            return Err(io_error("Not an ICMP error"));
+        }
+        Ok(ErrorReport {
+            len: message.len,
+            destination: message
+                .addr
+                .ok_or_else(|| io_error("Missing destination"))?,
+            offender: offender.ok_or_else(|| io_error("Missing offender"))?,
+            icmpv6: error.ee_origin == SO_EE_ORIGIN_ICMP6,
+            ty: error.ee_type,
+            code: error.ee_code,
+            info: error.ee_info,
+            timestamp: message.timestamp,
+        })
+    }
+
+    // Drain the transmit timestamps queued on the error queue, and return the latest one
+    pub fn last_send_timestamp(&self) -> Option<SystemTime> {
+        let mut buf = [0u8; 0];
+        let mut last = None;
+        while let Ok(message) = self.recvmsg(&mut buf, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) {
+            if message.timestamp.is_some() {
+                last = message.timestamp;
+            }
+        }
+        last
+    }
+}
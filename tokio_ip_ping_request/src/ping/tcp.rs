@@ -1,69 +1,106 @@
 use pnet::packet::icmp;
-use pnet::packet::icmp::echo_request;
 use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::tcp::{self, TcpFlags};
 use pnet::packet::Packet;
-use pnet::transport::icmp_packet_iter;
 use pnet::transport::transport_channel;
 use pnet::transport::TransportChannelType::Layer4;
 use pnet::transport::TransportProtocol::Ipv4;
-use pnet::transport::{TransportReceiver, TransportSender};
+use pnet::transport::TransportSender;
 use std::collections::BTreeMap;
-use std::net::IpAddr;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::sync::mpsc;
+use tokio::io::unix::AsyncFd;
 use tokio::sync::oneshot;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
 
+use super::socket::{self, Socket};
 use super::*;
 
+// Source ports are derived from the flow id, so that a fixed flow id keeps the whole
+// 5-tuple stable (Paris traceroute style). Probes are told apart by their sequence number.
+const SOURCE_PORT_OFFSET: u16 = 0x80_00;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortState {
+    // SYN-ACK
+    Open,
+    // RST
+    Closed,
+    // No TCP answer: timeout or ICMP destination unreachable
+    Filtered,
+}
+
+impl PortState {
+    // None when the probe did not get far enough to tell (TTL exceeded, send failure...)
+    pub fn from_result(result: &Result<TcpResponse, PingError>) -> Option<Self> {
+        match result {
+            Ok(response) => Some(response.state),
+            Err(PingError::Timeout { .. }) | Err(PingError::Unreachable { .. }) => {
+                Some(Self::Filtered)
+            }
+            Err(PingError::IcmpError { ty, .. })
+                if *ty == icmp::IcmpTypes::DestinationUnreachable =>
+            {
+                Some(Self::Filtered)
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TcpResponse {
+    pub state: PortState,
+    pub latency: Duration,
+}
+
+#[derive(Debug)]
+pub struct PingRequest {
+    addr: Ipv4Addr,
+    port: u16,
+    ttl: u8,
+    flow_id: u16,
+    timeout: Duration,
+    response_channel: oneshot::Sender<Result<TcpResponse, PingError>>,
+}
+
 #[derive(Debug)]
 pub struct OngoingRequest {
     start: Instant,
     stop: Instant,
-    response_channel: oneshot::Sender<Result<Duration, PingError>>,
+    response_channel: oneshot::Sender<Result<TcpResponse, PingError>>,
 }
 
+// Every field of the key is present both in TCP answers and in the TCP header quoted by
+// ICMP errors.
+type ProbeKey = (Ipv4Addr, u16, u32);
+
 #[derive(Debug, Clone, Copy)]
 pub struct PingIdentifier {
     responder: Ipv4Addr,
-    id: u16,
-    sn: u16,
-    stop: Instant,
     destination: Ipv4Addr,
+    source_port: u16,
+    seq: u32,
+    stop: Instant,
+    quoted: Option<Ipv4Header>,
 }
 
-#[derive(Debug, Clone)]
-pub enum PingRequestResponse {
-    PingResponse,
-    PingTimeExceeded,
-    IcmpError(Box<[u8]>),
-}
-
-impl PingRequestResponse {
-    fn build_input(self, id: PingIdentifier) -> Input {
-        match self {
-            Self::PingResponse => Input::PingResponse(id),
-            Self::PingTimeExceeded => Input::PingTimeExceeded(id),
-            Self::IcmpError(v) => {
-                let p = icmp::IcmpPacket::new(&v).unwrap();
-                let ty = p.get_icmp_type();
-                let code = p.get_icmp_code();
-                Input::IcmpError {
-                    id,
-                    ty,
-                    code,
-                    data: v,
-                }
-            }
-        }
+impl PingIdentifier {
+    fn key(&self) -> ProbeKey {
+        (self.destination, self.source_port, self.seq)
     }
 }
 
 #[derive(Debug)]
 pub enum Input {
     PingRequest(PingRequest),
-    PingResponse(PingIdentifier),
-    PingTimeExceeded(PingIdentifier),
+    TcpResponse {
+        id: PingIdentifier,
+        state: PortState,
+    },
+    PingTimeExceeded(PingIdentifier, Vec<MplsLabel>),
     IcmpError {
         id: PingIdentifier,
         ty: icmp::IcmpType,
@@ -71,274 +108,411 @@ pub enum Input {
         data: Box<[u8]>,
     },
     Timeout,
-    Stop,
+    // Signalled once the backend is done
+    Stop(oneshot::Sender<()>),
+}
+
+fn build_syn(
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    source_port: u16,
+    destination_port: u16,
+    seq: u32,
+) -> Vec<u8> {
+    let mut vec: Vec<u8> = vec![0; tcp::TcpPacket::minimum_packet_size()];
+    let mut syn_packet = tcp::MutableTcpPacket::new(&mut vec[..]).unwrap();
+    syn_packet.set_source(source_port);
+    syn_packet.set_destination(destination_port);
+    syn_packet.set_sequence(seq);
+    syn_packet.set_acknowledgement(0);
+    syn_packet.set_data_offset(5);
+    syn_packet.set_flags(TcpFlags::SYN);
+    syn_packet.set_window(0xFF_FF);
+    let csum = tcp::ipv4_checksum(&syn_packet.to_immutable(), &source, &destination);
+    syn_packet.set_checksum(csum);
+    vec
+}
+
+// Answer to one of our SYNs, in the IP packet `buf` from `addr`
+fn tcp_input(addr: Ipv4Addr, buf: &[u8]) -> Option<Input> {
+    let ipv4_packet = Ipv4Packet::new(buf)?;
+    let packet = tcp::TcpPacket::new(ipv4_packet.payload())?;
+    let flags = packet.get_flags();
+    if flags & TcpFlags::ACK == 0 {
+        return None;
+    }
+    let state = if flags & TcpFlags::RST != 0 {
+        PortState::Closed
+    } else if flags & TcpFlags::SYN != 0 {
+        PortState::Open
+    } else {
+        return None;
+    };
+    let id = PingIdentifier {
+        responder: addr,
+        destination: addr,
+        source_port: packet.get_destination(),
+        seq: packet.get_acknowledgement().wrapping_sub(1),
+        stop: Instant::now(),
+        quoted: None,
+    };
+    Some(Input::TcpResponse { id, state })
+}
+
+// ICMP error quoting one of our SYNs, in the IP packet `buf` from `addr`. Errors about TCP
+// segments that cannot be parsed are reported.
+fn icmp_input(
+    addr: Ipv4Addr,
+    buf: &[u8],
+    diagnostics: &broadcast::Sender<Diagnostic>,
+) -> Option<Input> {
+    let ip_packet = Ipv4Packet::new(buf)?;
+    let packet = icmp::IcmpPacket::new(ip_packet.payload())?;
+    let ty = packet.get_icmp_type();
+    if ty != icmp::IcmpTypes::TimeExceeded
+        && ty != icmp::IcmpTypes::DestinationUnreachable
+        && ty != icmp::IcmpTypes::ParameterProblem
+    {
+        return None;
+    }
+    let report = || {
+        let _ = diagnostics.send(unparseable(IpAddr::V4(addr), packet.packet()));
+    };
+    let error = match icmp::time_exceeded::TimeExceededPacket::new(packet.packet()) {
+        Some(error) => error,
+        None => {
+            report();
+            return None;
+        }
+    };
+    let ipv4_packet = match Ipv4Packet::new(error.payload()) {
+        Some(ipv4_packet) => ipv4_packet,
+        None => {
+            report();
+            return None;
+        }
+    };
+    if ipv4_packet.get_next_level_protocol() != IpNextHeaderProtocols::Tcp {
+        return None;
+    }
+    // Routers only have to quote the first 8 bytes of the segment: the ports and the
+    // sequence number.
+    let quoted = ipv4_packet.payload();
+    if quoted.len() < 8 {
+        report();
+        return None;
+    }
+
+    let id = PingIdentifier {
+        responder: addr,
+        destination: ipv4_packet.get_destination(),
+        source_port: u16::from_be_bytes([quoted[0], quoted[1]]),
+        seq: u32::from_be_bytes([quoted[4], quoted[5], quoted[6], quoted[7]]),
+        stop: Instant::now(),
+        quoted: Some(Ipv4Header::from_packet(&ipv4_packet)),
+    };
+
+    Some(if ty == icmp::IcmpTypes::TimeExceeded {
+        Input::PingTimeExceeded(id, mpls_labels(false, error.packet()))
+    } else {
+        Input::IcmpError {
+            id,
+            ty,
+            code: error.get_icmp_code(),
+            data: error.packet().into(),
+        }
+    })
 }
 
 pub struct PingerBackend {
-    // Size in bytes of the payload to send.  Default is 16 bytes
-    size: usize,
     // Request reception
     command_rx: mpsc::Receiver<Input>,
-    // Request sending
-    command_tx: mpsc::Sender<Input>,
-    // IPv4
+    // Fed by the listeners, which return once it is closed
+    reply_rx: mpsc::Receiver<Input>,
+    listeners: Vec<JoinHandle<()>>,
+    // IPv4 TCP
     tx: TransportSender,
 }
 
 impl PingerBackend {
     pub fn start(
-        size: u16,
+        parallelism: usize,
         command_rx: mpsc::Receiver<Input>,
-        command_tx: mpsc::Sender<Input>,
+        diagnostics: broadcast::Sender<Diagnostic>,
     ) -> Result<(), std::io::Error> {
-        // TODO We need Layer3 to have access to the IP header.
-        let protocol = Layer4(Ipv4(IpNextHeaderProtocols::Icmp));
-        let (tx, rx) = match transport_channel(4096, protocol) {
-            Ok((tx, rx)) => (tx, rx),
-            Err(e) => return Err(e),
-        };
+        let (reply_tx, reply_rx) = mpsc::channel(parallelism.max(1));
+        let protocol = Layer4(Ipv4(IpNextHeaderProtocols::Tcp));
+        let (tx, _) = transport_channel(4096, protocol)?;
+        // A raw TCP socket gets a copy of every TCP segment the host receives, which includes
+        // the answers to our SYNs. The kernel resets the half open connections by itself.
+        let rx = raw_ipv4_listener(libc::IPPROTO_TCP)?;
+        // ICMP errors quoting our SYNs go to ICMP sockets only
+        let icmp_rx = raw_ipv4_listener(libc::IPPROTO_ICMP)?;
 
-        let listener_tx = command_tx.clone();
-        std::thread::spawn(move || Self::run_ip_listener(rx, listener_tx));
+        let listeners = vec![
+            tokio::spawn(Self::run_tcp_listener(
+                rx,
+                reply_tx.clone(),
+                diagnostics.clone(),
+            )),
+            tokio::spawn(Self::run_icmp_listener(icmp_rx, reply_tx, diagnostics)),
+        ];
 
         let backend = Self {
-            size: size as usize,
             command_rx,
-            command_tx,
+            reply_rx,
+            listeners,
             tx,
         };
         tokio::spawn(backend.run());
         Ok(())
     }
 
-    pub fn run_ip_listener(mut rx: TransportReceiver, command_tx: mpsc::Sender<Input>) {
-        let mut iter = icmp_packet_iter(&mut rx);
+    pub async fn run_tcp_listener(
+        rx: AsyncFd<Arc<Socket>>,
+        reply_tx: mpsc::Sender<Input>,
+        diagnostics: broadcast::Sender<Diagnostic>,
+    ) {
+        let mut batch = socket::RecvBatch::new(LISTENER_BATCH_SIZE, HEADERS_SIZE);
+        let mut inputs = Vec::with_capacity(LISTENER_BATCH_SIZE);
         loop {
-            match iter.next() {
-                Ok((packet, addr)) => {
-                    let addr = match addr {
-                        IpAddr::V4(addr) => addr,
-                        _ => continue,
-                    };
-                    let command;
-                    if packet.get_icmp_type() == icmp::IcmpTypes::EchoReply {
-                        let packet =
-                            icmp::echo_reply::EchoReplyPacket::new(packet.packet()).unwrap();
-                        command = Some((
-                            PingRequestResponse::PingResponse,
-                            PingIdentifier {
-                                responder: addr,
-                                destination: addr,
-                                id: packet.get_identifier(),
-                                sn: packet.get_sequence_number(),
-                                stop: Instant::now(),
-                            },
-                        ));
-                    } else {
-                        let packet = if let Some(packet) =
-                            icmp::time_exceeded::TimeExceededPacket::new(packet.packet())
-                        {
-                            packet
-                        } else {
-                            continue;
-                        };
-                        let ipv4_packet = if let Some(packet) =
-                            pnet::packet::ipv4::Ipv4Packet::new(packet.payload())
-                        {
-                            packet
-                        } else {
-                            continue;
-                        };
-                        let icmp_packet = if let Some(packet) =
-                            echo_request::EchoRequestPacket::new(ipv4_packet.payload())
-                        {
-                            packet
-                        } else {
-                            continue;
-                        };
-
-                        let id = PingIdentifier {
-                            responder: addr,
-                            id: icmp_packet.get_identifier(),
-                            sn: icmp_packet.get_sequence_number(),
-                            destination: ipv4_packet.get_destination(),
-                            stop: Instant::now(),
-                        };
-
-                        if packet.get_icmp_type() == icmp::IcmpTypes::TimeExceeded {
-                            command = Some((PingRequestResponse::PingTimeExceeded, id));
-                        } else {
-                            command =
-                                Some((PingRequestResponse::IcmpError(packet.packet().into()), id));
-                        }
-                    }
-                    if let Some((command, id)) = command {
-                        loop {
-                            match command_tx.try_send(command.clone().build_input(id)) {
-                                Ok(_) => break,
-                                Err(mpsc::error::TrySendError::Full(_)) => {
-                                    std::thread::sleep(Duration::from_millis(100))
-                                }
-                                Err(mpsc::error::TrySendError::Closed(_)) => return,
-                            }
-                        }
-                    }
+            let count = tokio::select! {
+                count = receive_batch(&rx, &mut batch, &diagnostics) => count,
+                _ = reply_tx.closed() => return,
+            };
+            let count = match count {
+                Some(count) => count,
+                None => return,
+            };
+            for (received, buf) in (0..count).filter_map(|i| batch.get(i)) {
+                if let IpAddr::V4(addr) = received.addr {
+                    inputs.extend(tcp_input(addr, buf));
                 }
-                Err(e) => {
-                    // TODO Return proper errors somehow
-                    eprintln!("An error occurred while reading: {}", e);
+            }
+            // The batch is only read before sending, as sending may wait
+            for input in inputs.drain(..) {
+                if reply_tx.send(input).await.is_err() {
+                    return;
                 }
             }
         }
     }
 
-    async fn start_timeout(tx: mpsc::Sender<Input>, timeout: Duration) {
-        let tx = tx.clone();
-        tokio::time::sleep(timeout).await;
-        let _ = tx.send(Input::Timeout).await;
+    pub async fn run_icmp_listener(
+        rx: AsyncFd<Arc<Socket>>,
+        reply_tx: mpsc::Sender<Input>,
+        diagnostics: broadcast::Sender<Diagnostic>,
+    ) {
+        let mut batch = socket::RecvBatch::new(LISTENER_BATCH_SIZE, HEADERS_SIZE);
+        let mut inputs = Vec::with_capacity(LISTENER_BATCH_SIZE);
+        loop {
+            let count = tokio::select! {
+                count = receive_batch(&rx, &mut batch, &diagnostics) => count,
+                _ = reply_tx.closed() => return,
+            };
+            let count = match count {
+                Some(count) => count,
+                None => return,
+            };
+            for (received, buf) in (0..count).filter_map(|i| batch.get(i)) {
+                if let IpAddr::V4(addr) = received.addr {
+                    inputs.extend(icmp_input(addr, buf, &diagnostics));
+                }
+            }
+            // The batch is only read before sending, as sending may wait
+            for input in inputs.drain(..) {
+                if reply_tx.send(input).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn latency(ongoing: &OngoingRequest, id: &PingIdentifier) -> Duration {
+        if id.stop > ongoing.start {
+            id.stop.duration_since(ongoing.start)
+        } else {
+            Duration::from_nanos(10)
+        }
     }
 
     async fn run(self) {
         let Self {
-            size,
             mut command_rx,
-            command_tx,
+            mut reply_rx,
+            listeners,
             mut tx,
         } = self;
-        let mut index = 0u16;
-        let mut timer_running = false;
-        let mut ongoing = BTreeMap::new();
+        let mut seq = 0u32;
+        let mut ongoing: BTreeMap<ProbeKey, OngoingRequest> = BTreeMap::new();
+        let mut deadlines = Deadlines::new();
         let mut last_ttl = 0;
+        let mut done = None;
 
-        while let Some(input) = command_rx.recv().await {
+        while let Some(input) = next_input(
+            &mut command_rx,
+            &mut reply_rx,
+            deadlines.next(),
+            Input::Timeout,
+        )
+        .await
+        {
             match input {
                 Input::PingRequest(request) => {
-                    let mut vec: Vec<u8> = vec![0; size];
-                    let mut echo_packet =
-                        echo_request::MutableEchoRequestPacket::new(&mut vec[..]).unwrap();
-                    let id = ((index as u32 + request.flow_id as u32) % 0x1_00_00) as u16;
-                    let sn = 0xFF_FF - index;
-                    echo_packet.set_identifier(id);
-                    echo_packet.set_sequence_number(sn);
-                    echo_packet.set_icmp_type(icmp::IcmpTypes::EchoRequest);
-                    let csum = pnet::util::checksum(echo_packet.packet(), 1);
-                    echo_packet.set_checksum(csum);
+                    let source_port = request.flow_id.wrapping_add(SOURCE_PORT_OFFSET);
+                    let source = match source_address(request.addr, request.port) {
+                        Ok(source) => source,
+                        Err(_) => {
+                            let _ = request
+                                .response_channel
+                                .send(Err(PingError::FailedToSendPacket));
+                            continue;
+                        }
+                    };
+                    let vec = build_syn(source, request.addr, source_port, request.port, seq);
+                    let syn_packet = tcp::TcpPacket::new(&vec[..]).unwrap();
 
                     if request.ttl != last_ttl {
-                        tx.set_ttl(request.ttl).unwrap();
+                        if tx.set_ttl(request.ttl).is_err() {
+                            let _ = request
+                                .response_channel
+                                .send(Err(PingError::FailedToSendPacket));
+                            continue;
+                        }
                         last_ttl = request.ttl;
                     }
-                    if tx.send_to(echo_packet, IpAddr::V4(request.addr)).is_err() {
-                        // TODO Signal error?
+                    if tx.send_to(syn_packet, IpAddr::V4(request.addr)).is_err() {
                         let _ = request
                             .response_channel
                             .send(Err(PingError::FailedToSendPacket));
                     } else {
                         let start = Instant::now();
+                        let key = (request.addr, source_port, seq);
+                        let stop = start + request.timeout;
+                        deadlines.push(stop, key);
                         ongoing.insert(
-                            (request.addr, id),
+                            key,
                             OngoingRequest {
                                 start,
-                                stop: start + request.timeout,
+                                stop,
                                 response_channel: request.response_channel,
                             },
                         );
-                        index += 1;
-                        if !timer_running {
-                            tokio::spawn(Self::start_timeout(command_tx.clone(), request.timeout));
-                            timer_running = true;
-                        }
+                        seq = seq.wrapping_add(1);
                     }
                 }
-                Input::PingResponse(response) => {
-                    if let Some(ongoing) = ongoing.remove(&(response.destination, response.id)) {
-                        let duration = if response.stop > ongoing.start {
-                            response.stop.duration_since(ongoing.start)
-                        } else {
-                            Duration::from_nanos(10)
-                        };
-                        let _ = ongoing.response_channel.send(Ok(duration));
+                Input::TcpResponse { id, state } => {
+                    if let Some(ongoing) = ongoing.remove(&id.key()) {
+                        let latency = Self::latency(&ongoing, &id);
+                        let _ = ongoing
+                            .response_channel
+                            .send(Ok(TcpResponse { state, latency }));
                     }
                 }
-                Input::PingTimeExceeded(response) => {
-                    if let Some(ongoing) = ongoing.remove(&(response.destination, response.id)) {
-                        let latency = if response.stop > ongoing.start {
-                            response.stop.duration_since(ongoing.start)
-                        } else {
-                            Duration::from_nanos(10)
-                        };
+                Input::PingTimeExceeded(id, mpls) => {
+                    if let Some(ongoing) = ongoing.remove(&id.key()) {
+                        let latency = Self::latency(&ongoing, &id);
                         let _ = ongoing.response_channel.send(Err(PingError::TimeExceeded {
-                            addr: response.responder,
+                            addr: IpAddr::V4(id.responder),
                             latency,
+                            ip: IpHeaders {
+                                reply: None,
+                                quoted: id.quoted,
+                            },
+                            mpls,
                         }));
                     }
                 }
                 Input::IcmpError { id, code, ty, data } => {
-                    if let Some(ongoing) = ongoing.remove(&(id.destination, id.id)) {
-                        let latency = if id.stop > ongoing.start {
-                            id.stop.duration_since(ongoing.start)
-                        } else {
-                            Duration::from_nanos(10)
-                        };
-                        let _ = ongoing.response_channel.send(Err(PingError::IcmpError {
+                    if let Some(ongoing) = ongoing.remove(&id.key()) {
+                        let latency = Self::latency(&ongoing, &id);
+                        let _ = ongoing.response_channel.send(Err(PingError::from_icmp(
+                            IpAddr::V4(id.responder),
                             ty,
                             code,
                             data,
-                            responder: id.responder,
+                            true,
                             latency,
-                        }));
+                            IpHeaders {
+                                reply: None,
+                                quoted: id.quoted,
+                            },
+                        )));
                     }
                 }
                 Input::Timeout => {
                     let now = Instant::now();
-                    let lost: Vec<_> = ongoing
-                        .iter()
-                        .filter(|(_, v)| now > v.stop)
-                        .map(|(k, _)| *k)
-                        .collect();
-                    for k in lost.into_iter() {
-                        let v = ongoing.remove(&k).unwrap();
-                        let _ = v.response_channel.send(Err(PingError::Timeout));
-                    }
-                    if !ongoing.is_empty() {
-                        let earliest_timeout = ongoing.values().map(|v| v.stop).min().unwrap();
-                        let delay = earliest_timeout.duration_since(now);
-                        tokio::spawn(Self::start_timeout(command_tx.clone(), delay));
-                    } else {
-                        timer_running = false;
+                    while let Some(key) = deadlines.pop_expired(now) {
+                        // The key may have been reused by a later request
+                        if matches!(ongoing.get(&key), Some(v) if v.stop <= now) {
+                            let v = ongoing.remove(&key).unwrap();
+                            let _ = v
+                                .response_channel
+                                .send(Err(PingError::Timeout { echo: None }));
+                        }
                     }
                 }
-                Input::Stop => break,
+                Input::Stop(tx) => {
+                    done = Some(tx);
+                    break;
+                }
             }
         }
+
+        // Every pinger is gone, or one of them shut the backend down
+        for (_, request) in ongoing {
+            let _ = request.response_channel.send(Err(PingError::BackendClosed));
+        }
+        // The listeners return once the reply channel is closed, and release their sockets
+        drop(reply_rx);
+        for listener in listeners {
+            let _ = listener.await;
+        }
+        drop(tx);
+        if let Some(done) = done {
+            let _ = done.send(());
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct Pinger {
     command_tx: mpsc::Sender<Input>,
+    diagnostics: broadcast::Sender<Diagnostic>,
 }
 
 impl Pinger {
-    // initialize the pinger and start the icmp and icmpv6 listeners
-    pub fn new(size: u16, parallelism: usize) -> Result<Self, std::io::Error> {
+    // initialize the pinger and start the tcp and icmp listeners
+    pub fn new(parallelism: usize) -> Result<Self, std::io::Error> {
         let (command_tx, command_rx) = mpsc::channel(parallelism);
-        PingerBackend::start(size, command_rx, command_tx.clone())?;
-        Ok(Self { command_tx })
+        let (diagnostics, _) = broadcast::channel(super::icmp::DIAGNOSTICS_CAPACITY);
+        PingerBackend::start(parallelism, command_rx, diagnostics.clone())?;
+        Ok(Self {
+            command_tx,
+            diagnostics,
+        })
     }
 
+    // Events from now on, like those of the ICMP pinger
+    pub fn diagnostics(&self) -> broadcast::Receiver<Diagnostic> {
+        self.diagnostics.subscribe()
+    }
+
+    // Send a SYN to addr:port. The flow id selects the source port.
     pub async fn ping(
         &self,
         addr: Ipv4Addr,
+        port: u16,
         ttl: u8,
         timeout: Duration,
         flow_id: u16,
-    ) -> Result<Duration, PingError> {
+    ) -> Result<TcpResponse, PingError> {
         let (tx, rx) = oneshot::channel();
         if self
             .command_tx
             .send(Input::PingRequest(PingRequest {
                 addr,
+                port,
                 ttl,
                 flow_id,
                 timeout,
@@ -352,8 +526,132 @@ impl Pinger {
         rx.await.unwrap_or(Err(PingError::BackendClosed))
     }
 
-    // TODO This has to be called to stop the backend thread
-    pub async fn stop(self) {
-        let _ = self.command_tx.send(Input::Stop).await;
+    // Stop the backend, for every clone of the pinger, and wait until its sockets are closed.
+    // Dropping the last clone does the same in the background. Either way, the requests still
+    // waiting fail with `BackendClosed`.
+    pub async fn shutdown(self) {
+        let (tx, rx) = oneshot::channel();
+        if self.command_tx.send(Input::Stop(tx)).await.is_ok() {
+            let _ = rx.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pnet::packet::ip::IpNextHeaderProtocol;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+
+    const SOURCE: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const TARGET: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 1);
+
+    fn ipv4_packet(
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+        protocol: IpNextHeaderProtocol,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let header_size = Ipv4Packet::minimum_packet_size();
+        let mut vec = vec![0; header_size + payload.len()];
+        let len = vec.len() as u16;
+        let mut packet = MutableIpv4Packet::new(&mut vec).unwrap();
+        packet.set_version(4);
+        packet.set_header_length((header_size / 4) as u8);
+        packet.set_total_length(len);
+        packet.set_ttl(64);
+        packet.set_next_level_protocol(protocol);
+        packet.set_source(source);
+        packet.set_destination(destination);
+        packet.set_payload(payload);
+        vec
+    }
+
+    // Answer of TARGET:80 to the SYN `seq` from port 0x80_01, with the flags `set_flags` sets
+    fn answer(set_flags: impl FnOnce(&mut tcp::MutableTcpPacket), seq: u32) -> Vec<u8> {
+        let mut segment = build_syn(TARGET, SOURCE, 80, 0x80_01, 1000);
+        let mut packet = tcp::MutableTcpPacket::new(&mut segment).unwrap();
+        set_flags(&mut packet);
+        packet.set_acknowledgement(seq.wrapping_add(1));
+        ipv4_packet(TARGET, SOURCE, IpNextHeaderProtocols::Tcp, &segment)
+    }
+
+    #[test]
+    fn answers_give_the_port_state() {
+        let syn_ack = |p: &mut tcp::MutableTcpPacket| p.set_flags(TcpFlags::SYN | TcpFlags::ACK);
+        let rst = |p: &mut tcp::MutableTcpPacket| p.set_flags(TcpFlags::RST | TcpFlags::ACK);
+        match tcp_input(TARGET, &answer(syn_ack, 7)) {
+            Some(Input::TcpResponse { id, state }) => {
+                assert_eq!(state, PortState::Open);
+                assert_eq!(id.key(), (TARGET, 0x80_01, 7));
+            }
+            input => panic!("{:?}", input),
+        }
+        match tcp_input(TARGET, &answer(rst, 7)) {
+            Some(Input::TcpResponse { id, state }) => {
+                assert_eq!(state, PortState::Closed);
+                assert_eq!(id.key(), (TARGET, 0x80_01, 7));
+            }
+            input => panic!("{:?}", input),
+        }
+        // The acknowledgement number wraps around
+        match tcp_input(TARGET, &answer(syn_ack, u32::MAX)) {
+            Some(Input::TcpResponse { id, .. }) => {
+                assert_eq!(id.key(), (TARGET, 0x80_01, u32::MAX))
+            }
+            input => panic!("{:?}", input),
+        }
+        // Not answers to a SYN
+        let syn = |p: &mut tcp::MutableTcpPacket| p.set_flags(TcpFlags::SYN);
+        assert!(tcp_input(TARGET, &answer(syn, 7)).is_none());
+        let ack = |p: &mut tcp::MutableTcpPacket| p.set_flags(TcpFlags::ACK);
+        assert!(tcp_input(TARGET, &answer(ack, 7)).is_none());
+    }
+
+    #[test]
+    fn icmp_errors_quote_the_probe_key() {
+        let router = Ipv4Addr::new(203, 0, 113, 1);
+        let syn = build_syn(SOURCE, TARGET, 0x80_01, 80, 7);
+        let quoted = ipv4_packet(SOURCE, TARGET, IpNextHeaderProtocols::Tcp, &syn);
+        let error = |ty: u8, code: u8, quoted: &[u8]| {
+            let mut message = vec![ty, code, 0, 0, 0, 0, 0, 0];
+            message.extend_from_slice(quoted);
+            ipv4_packet(router, SOURCE, IpNextHeaderProtocols::Icmp, &message)
+        };
+        let (diagnostics, mut diagnostics_rx) = broadcast::channel(8);
+
+        match icmp_input(router, &error(11, 0, &quoted), &diagnostics) {
+            Some(Input::PingTimeExceeded(id, _)) => {
+                assert_eq!(id.key(), (TARGET, 0x80_01, 7));
+                assert_eq!(id.responder, router);
+            }
+            input => panic!("{:?}", input),
+        }
+        // Port unreachable
+        match icmp_input(router, &error(3, 3, &quoted), &diagnostics) {
+            Some(Input::IcmpError { id, ty, code, .. }) => {
+                assert_eq!(id.key(), (TARGET, 0x80_01, 7));
+                assert_eq!(
+                    (ty, code),
+                    (icmp::IcmpTypes::DestinationUnreachable, icmp::IcmpCode(3))
+                );
+            }
+            input => panic!("{:?}", input),
+        }
+        // Routers quote at least 8 bytes of the segment
+        let truncated = &quoted[..quoted.len() - syn.len() + 8];
+        assert!(icmp_input(router, &error(11, 0, truncated), &diagnostics).is_some());
+        let truncated = &truncated[..truncated.len() - 1];
+        assert!(icmp_input(router, &error(11, 0, truncated), &diagnostics).is_none());
+        assert!(matches!(
+            diagnostics_rx.try_recv(),
+            Ok(Diagnostic::Unparseable { .. })
+        ));
+
+        // Errors about other protocols, and other ICMP messages
+        let udp = ipv4_packet(SOURCE, TARGET, IpNextHeaderProtocols::Udp, &syn);
+        assert!(icmp_input(router, &error(11, 0, &udp), &diagnostics).is_none());
+        assert!(icmp_input(router, &error(0, 0, &quoted), &diagnostics).is_none());
+        assert!(diagnostics_rx.try_recv().is_err());
     }
 }
@@ -0,0 +1,72 @@
+pub use super::socket::PacketOptions;
+use super::socket::Socket;
+use std::net::IpAddr;
+use std::time::SystemTime;
+
+// Packet a transport hands to its pinger
+#[derive(Debug, Clone)]
+pub struct Incoming {
+    // Host that sent the packet
+    pub source: IpAddr,
+    // IPv4 packet with its header, or ICMPv6 message, as raw sockets deliver them
+    pub data: Box<[u8]>,
+    pub timestamp: Option<SystemTime>,
+}
+
+// Where an ICMP pinger sends its probes. The packets for the pinger come back through the
+// channel given to icmp::Pinger::with_transport.
+pub trait Transport: Send + Sync {
+    // `packet` is an ICMP(v6) echo request, or an IPv4 packet for pingers with `ip_header`
+    fn send_to(
+        &self,
+        packet: &[u8],
+        addr: IpAddr,
+        options: &PacketOptions,
+    ) -> Result<usize, std::io::Error>;
+
+    // Number of packets sent, stopping at the first error
+    fn send_batch(
+        &self,
+        packets: &mut dyn Iterator<Item = (&[u8], IpAddr, PacketOptions)>,
+    ) -> usize {
+        packets
+            .take_while(|(packet, addr, options)| self.send_to(packet, *addr, options).is_ok())
+            .count()
+    }
+
+    // DF bit of the IPv4 packets to come. None leaves it to path MTU discovery.
+    fn set_dont_fragment(&self, _dont_fragment: Option<bool>) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    // When the last packet was sent, if the transport knows better than the pinger
+    fn last_send_timestamp(&self) -> Option<SystemTime> {
+        None
+    }
+}
+
+impl Transport for Socket {
+    fn send_to(
+        &self,
+        packet: &[u8],
+        addr: IpAddr,
+        options: &PacketOptions,
+    ) -> Result<usize, std::io::Error> {
+        Socket::send_to(self, packet, addr, options)
+    }
+
+    fn send_batch(
+        &self,
+        packets: &mut dyn Iterator<Item = (&[u8], IpAddr, PacketOptions)>,
+    ) -> usize {
+        Socket::send_batch(self, packets)
+    }
+
+    fn set_dont_fragment(&self, dont_fragment: Option<bool>) -> Result<(), std::io::Error> {
+        Socket::set_dont_fragment(self, dont_fragment)
+    }
+
+    fn last_send_timestamp(&self) -> Option<SystemTime> {
+        Socket::last_send_timestamp(self)
+    }
+}
@@ -0,0 +1,308 @@
+use super::simulation::SimulatedNetwork;
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+use tokio::io::unix::AsyncFd;
+use tokio::task::JoinHandle;
+
+const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+const IFF_TUN: libc::c_short = 0x0001;
+const IFF_NO_PI: libc::c_short = 0x1000;
+// Largest IP packet
+const BUFFER_SIZE: usize = 0xFF_FF;
+
+#[repr(C)]
+union IfReqData {
+    flags: libc::c_short,
+    addr: libc::sockaddr_in,
+    _size: [u8; 24],
+}
+
+// struct ifreq of the interface ioctls
+#[repr(C)]
+struct IfReq {
+    name: [libc::c_char; libc::IFNAMSIZ],
+    data: IfReqData,
+}
+
+impl IfReq {
+    fn new(name: &str) -> Result<Self, io::Error> {
+        if name.is_empty() || name.len() >= libc::IFNAMSIZ || name.contains('\0') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Bad interface name: {}", name),
+            ));
+        }
+        let mut req: Self = unsafe { mem::zeroed() };
+        for (c, b) in req.name.iter_mut().zip(name.bytes()) {
+            *c = b as libc::c_char;
+        }
+        Ok(req)
+    }
+
+    fn with_addr(name: &str, addr: Ipv4Addr) -> Result<Self, io::Error> {
+        let mut req = Self::new(name)?;
+        let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+        sin.sin_family = libc::AF_INET as libc::sa_family_t;
+        sin.sin_addr.s_addr = u32::from(addr).to_be();
+        req.data.addr = sin;
+        Ok(req)
+    }
+}
+
+fn ioctl(fd: RawFd, request: libc::c_ulong, req: &mut IfReq) -> Result<(), io::Error> {
+    if unsafe { libc::ioctl(fd, request as _, req as *mut IfReq) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn open_tun(name: &str) -> Result<OwnedFd, io::Error> {
+    let mut req = IfReq::new(name)?;
+    let fd = unsafe {
+        libc::open(
+            b"/dev/net/tun\0".as_ptr() as *const libc::c_char,
+            libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    req.data.flags = IFF_TUN | IFF_NO_PI;
+    ioctl(fd.as_raw_fd(), TUNSETIFF, &mut req)?;
+    Ok(fd)
+}
+
+// Give the interface its address and bring it up
+fn configure(name: &str, addr: Ipv4Addr, prefix_len: u8) -> Result<(), io::Error> {
+    let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if socket < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(socket) };
+    let fd = socket.as_raw_fd();
+    ioctl(
+        fd,
+        libc::SIOCSIFADDR as _,
+        &mut IfReq::with_addr(name, addr)?,
+    )?;
+    let netmask = u32::MAX
+        .checked_shl(32 - prefix_len.min(32) as u32)
+        .unwrap_or(0);
+    ioctl(
+        fd,
+        libc::SIOCSIFNETMASK as _,
+        &mut IfReq::with_addr(name, Ipv4Addr::from(netmask))?,
+    )?;
+    let mut req = IfReq::new(name)?;
+    ioctl(fd, libc::SIOCGIFFLAGS as _, &mut req)?;
+    unsafe { req.data.flags |= libc::IFF_UP as libc::c_short };
+    ioctl(fd, libc::SIOCSIFFLAGS as _, &mut req)
+}
+
+// TUN interface answering the packets routed through it from a simulated network, so that
+// real sockets can probe the network. The interface goes away with the TunNetwork.
+pub struct TunNetwork {
+    name: String,
+    task: JoinHandle<()>,
+}
+
+impl TunNetwork {
+    // Interface `name` with address `addr`, to which the `addr`/`prefix_len` subnet routes.
+    // The routes of `network` must lead to that subnet. Needs a Tokio runtime and
+    // CAP_NET_ADMIN.
+    pub fn new(
+        name: &str,
+        addr: Ipv4Addr,
+        prefix_len: u8,
+        network: SimulatedNetwork,
+    ) -> Result<Self, io::Error> {
+        let fd = open_tun(name)?;
+        configure(name, addr, prefix_len)?;
+        let fd = Arc::new(AsyncFd::new(fd)?);
+        Ok(Self {
+            name: name.to_string(),
+            task: tokio::spawn(Self::run(fd, network)),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn run(fd: Arc<AsyncFd<OwnedFd>>, network: SimulatedNetwork) {
+        let mut buf = vec![0; BUFFER_SIZE];
+        loop {
+            let len = match read(&fd, &mut buf).await {
+                Ok(len) => len,
+                Err(_) => return,
+            };
+            // Anything else than echo requests sent to the network is dropped
+            if let Some((delay, answer, copies)) = network.answer(&buf[..len]) {
+                let fd = fd.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    // A TUN interface takes a whole packet per write, and drops it when full
+                    for _ in 0..copies {
+                        unsafe {
+                            libc::write(
+                                fd.as_raw_fd(),
+                                answer.as_ptr() as *const libc::c_void,
+                                answer.len(),
+                            )
+                        };
+                    }
+                });
+            }
+        }
+    }
+}
+
+impl Drop for TunNetwork {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn read(fd: &AsyncFd<OwnedFd>, buf: &mut [u8]) -> Result<usize, io::Error> {
+    loop {
+        let mut guard = fd.readable().await?;
+        let result = guard.try_io(|fd| {
+            let len = unsafe {
+                libc::read(
+                    fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(len as usize)
+        });
+        match result {
+            Ok(result) => return result,
+            Err(_) => continue,
+        }
+    }
+}
+
+// Raw socket pinger bound to a TUN interface for `network`. The tests using it are ignored by
+// default, as they need CAP_NET_ADMIN and CAP_NET_RAW: run them with `cargo test -- --ignored`.
+#[cfg(test)]
+pub fn test_pinger(
+    name: &str,
+    addr: Ipv4Addr,
+    network: SimulatedNetwork,
+) -> (TunNetwork, super::icmp::Pinger) {
+    test_pinger_with_options(name, addr, network, Default::default())
+}
+
+// The socket mode and the device of `options` are those of the interface
+#[cfg(test)]
+pub fn test_pinger_with_options(
+    name: &str,
+    addr: Ipv4Addr,
+    network: SimulatedNetwork,
+    options: super::icmp::PingerOptions,
+) -> (TunNetwork, super::icmp::Pinger) {
+    use super::icmp::{Pinger, PingerOptions, SocketMode};
+
+    let tun = TunNetwork::new(name, addr, 24, network).expect("TUN interface");
+    let pinger = Pinger::with_options(PingerOptions {
+        socket_mode: SocketMode::Raw,
+        device: Some(name.to_string()),
+        ..options
+    })
+    .expect("Raw socket");
+    (tun, pinger)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::icmp::{Pinger, PingerOptions};
+    use super::super::simulation::{Hop, SimulatedNetwork};
+    use super::super::{ClockSource, PingError};
+    use super::*;
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    const LATENCY: Duration = Duration::from_millis(1);
+
+    // One router before 198.18.0.100
+    fn network() -> SimulatedNetwork {
+        SimulatedNetwork::new(1).route(
+            Hop::new("198.18.0.100".parse().unwrap(), LATENCY),
+            vec![vec![Hop::new("198.18.0.11".parse().unwrap(), LATENCY)]],
+        )
+    }
+
+    async fn check_ping(pinger: Pinger) {
+        let timeout = Duration::from_secs(1);
+        let host: IpAddr = "198.18.0.100".parse().unwrap();
+        let router: IpAddr = "198.18.0.11".parse().unwrap();
+
+        let reply = pinger.ping(host, 64, timeout, 0).await.unwrap();
+        assert_eq!(reply.responder, host);
+        assert!(reply.rtt >= LATENCY * 4);
+        match pinger.ping(host, 1, timeout, 0).await {
+            Err(PingError::TimeExceeded { addr, .. }) => assert_eq!(addr, router),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        // Not routed by the simulated network
+        let unknown = "198.18.0.200".parse().unwrap();
+        let result = pinger
+            .ping(unknown, 64, Duration::from_millis(100), 0)
+            .await;
+        assert!(matches!(result, Err(PingError::Timeout { .. })));
+        pinger.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn ping_through_simulation() {
+        check_ping(network().pinger(PingerOptions::default())).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs CAP_NET_ADMIN and CAP_NET_RAW"]
+    async fn ping_through_tun() {
+        let (tun, pinger) = test_pinger("pingtest0", Ipv4Addr::new(198, 18, 0, 1), network());
+        check_ping(pinger).await;
+        drop(tun);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs CAP_NET_ADMIN and CAP_NET_RAW"]
+    async fn batched_kernel_timestamps() {
+        let host: IpAddr = "198.18.3.100".parse().unwrap();
+        let network = SimulatedNetwork::new(1).route(Hop::new(host, LATENCY), vec![]);
+        let options = PingerOptions {
+            batch_size: 8,
+            kernel_timestamps: true,
+            ..PingerOptions::default()
+        };
+        let (tun, pinger) =
+            test_pinger_with_options("pingtest3", Ipv4Addr::new(198, 18, 3, 1), network, options);
+        // Several batches, each leaving its transmit timestamps on the error queue
+        for _ in 0..4 {
+            let pings: Vec<_> = (0..32)
+                .map(|_| {
+                    let pinger = pinger.clone();
+                    tokio::spawn(
+                        async move { pinger.ping(host, 64, Duration::from_secs(1), 0).await },
+                    )
+                })
+                .collect();
+            for ping in pings {
+                let reply = ping.await.unwrap().unwrap();
+                assert_eq!(reply.clock, ClockSource::Kernel);
+                assert!(reply.rtt >= LATENCY * 2, "{:?}", reply.rtt);
+            }
+        }
+        pinger.shutdown().await;
+        drop(tun);
+    }
+}
@@ -0,0 +1,504 @@
+use pnet::packet::icmp;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::udp;
+use pnet::packet::Packet;
+use pnet::transport::transport_channel;
+use pnet::transport::TransportChannelType::Layer4;
+use pnet::transport::TransportProtocol::Ipv4;
+use pnet::transport::TransportSender;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::oneshot;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+
+use super::socket::{self, Socket};
+use super::*;
+
+// Same as the TCP pinger: the flow id selects the source port.
+const SOURCE_PORT_OFFSET: u16 = 0x80_00;
+// First destination port of classic traceroute
+pub const TRACEROUTE_PORT: u16 = 33434;
+
+#[derive(Debug)]
+pub struct PingRequest {
+    addr: Ipv4Addr,
+    port: u16,
+    ttl: u8,
+    flow_id: u16,
+    timeout: Duration,
+    response_channel: oneshot::Sender<Result<Duration, PingError>>,
+}
+
+#[derive(Debug)]
+pub struct OngoingRequest {
+    start: Instant,
+    stop: Instant,
+    response_channel: oneshot::Sender<Result<Duration, PingError>>,
+}
+
+// The ports may stay the same for a whole flow, so probes are told apart by their checksum,
+// Paris traceroute style. All four fields are quoted by ICMP errors.
+type ProbeKey = (Ipv4Addr, u16, u16, u16);
+
+#[derive(Debug, Clone, Copy)]
+pub struct PingIdentifier {
+    responder: Ipv4Addr,
+    destination: Ipv4Addr,
+    source_port: u16,
+    destination_port: u16,
+    checksum: u16,
+    stop: Instant,
+    quoted: Option<Ipv4Header>,
+}
+
+impl PingIdentifier {
+    fn key(&self) -> ProbeKey {
+        (
+            self.destination,
+            self.source_port,
+            self.destination_port,
+            self.checksum,
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum Input {
+    PingRequest(PingRequest),
+    PingTimeExceeded(PingIdentifier, Vec<MplsLabel>),
+    IcmpError {
+        id: PingIdentifier,
+        ty: icmp::IcmpType,
+        code: icmp::IcmpCode,
+        data: Box<[u8]>,
+    },
+    Timeout,
+    // Signalled once the backend is done
+    Stop(oneshot::Sender<()>),
+}
+
+// Build a datagram whose checksum is `checksum`, by tuning the first two bytes of the payload.
+// `checksum` must be within 1..=0xFFFE: 0 means "no checksum" in UDP over IPv4 and a sum of
+// 0xFFFF is sent as 0.
+fn build_datagram(
+    size: usize,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    source_port: u16,
+    destination_port: u16,
+    checksum: u16,
+) -> Vec<u8> {
+    let header_size = udp::UdpPacket::minimum_packet_size();
+    let mut vec: Vec<u8> = vec![0; header_size + size.max(2)];
+    let len = vec.len() as u16;
+    let mut udp_packet = udp::MutableUdpPacket::new(&mut vec[..]).unwrap();
+    udp_packet.set_source(source_port);
+    udp_packet.set_destination(destination_port);
+    udp_packet.set_length(len);
+    let base = udp::ipv4_checksum(&udp_packet.to_immutable(), &source, &destination);
+    // Adding w to the one's complement sum turns the checksum c into c - w
+    let adjust = ones_complement_add(base, !checksum);
+    udp_packet.set_payload(&adjust.to_be_bytes());
+    let csum = udp::ipv4_checksum(&udp_packet.to_immutable(), &source, &destination);
+    udp_packet.set_checksum(csum);
+    vec
+}
+
+// ICMP error quoting one of our datagrams, in the IP packet `buf` from `addr`. Errors about
+// datagrams that cannot be parsed are reported.
+fn icmp_input(
+    addr: Ipv4Addr,
+    buf: &[u8],
+    diagnostics: &broadcast::Sender<Diagnostic>,
+) -> Option<Input> {
+    let ip_packet = Ipv4Packet::new(buf)?;
+    let packet = icmp::IcmpPacket::new(ip_packet.payload())?;
+    let ty = packet.get_icmp_type();
+    if ty != icmp::IcmpTypes::TimeExceeded
+        && ty != icmp::IcmpTypes::DestinationUnreachable
+        && ty != icmp::IcmpTypes::ParameterProblem
+    {
+        return None;
+    }
+    let report = || {
+        let _ = diagnostics.send(unparseable(IpAddr::V4(addr), packet.packet()));
+    };
+    let error = match icmp::time_exceeded::TimeExceededPacket::new(packet.packet()) {
+        Some(error) => error,
+        None => {
+            report();
+            return None;
+        }
+    };
+    let ipv4_packet = match Ipv4Packet::new(error.payload()) {
+        Some(ipv4_packet) => ipv4_packet,
+        None => {
+            report();
+            return None;
+        }
+    };
+    if ipv4_packet.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
+        return None;
+    }
+    let udp_packet = match udp::UdpPacket::new(ipv4_packet.payload()) {
+        Some(udp_packet) => udp_packet,
+        None => {
+            report();
+            return None;
+        }
+    };
+
+    let id = PingIdentifier {
+        responder: addr,
+        destination: ipv4_packet.get_destination(),
+        source_port: udp_packet.get_source(),
+        destination_port: udp_packet.get_destination(),
+        checksum: udp_packet.get_checksum(),
+        stop: Instant::now(),
+        quoted: Some(Ipv4Header::from_packet(&ipv4_packet)),
+    };
+
+    Some(if ty == icmp::IcmpTypes::TimeExceeded {
+        Input::PingTimeExceeded(id, mpls_labels(false, error.packet()))
+    } else {
+        Input::IcmpError {
+            id,
+            ty,
+            code: error.get_icmp_code(),
+            data: error.packet().into(),
+        }
+    })
+}
+
+pub struct PingerBackend {
+    // Size in bytes of the payload to send
+    size: usize,
+    // Request reception
+    command_rx: mpsc::Receiver<Input>,
+    // Fed by the listener, which returns once it is closed
+    reply_rx: mpsc::Receiver<Input>,
+    listener: JoinHandle<()>,
+    // IPv4 UDP
+    tx: TransportSender,
+}
+
+impl PingerBackend {
+    pub fn start(
+        size: u16,
+        parallelism: usize,
+        command_rx: mpsc::Receiver<Input>,
+        diagnostics: broadcast::Sender<Diagnostic>,
+    ) -> Result<(), std::io::Error> {
+        let (reply_tx, reply_rx) = mpsc::channel(parallelism.max(1));
+        let protocol = Layer4(Ipv4(IpNextHeaderProtocols::Udp));
+        let (tx, _) = transport_channel(4096, protocol)?;
+        let icmp_rx = raw_ipv4_listener(libc::IPPROTO_ICMP)?;
+
+        let listener = tokio::spawn(Self::run_icmp_listener(icmp_rx, reply_tx, diagnostics));
+
+        let backend = Self {
+            size: size as usize,
+            command_rx,
+            reply_rx,
+            listener,
+            tx,
+        };
+        tokio::spawn(backend.run());
+        Ok(())
+    }
+
+    pub async fn run_icmp_listener(
+        rx: AsyncFd<Arc<Socket>>,
+        reply_tx: mpsc::Sender<Input>,
+        diagnostics: broadcast::Sender<Diagnostic>,
+    ) {
+        let mut batch = socket::RecvBatch::new(LISTENER_BATCH_SIZE, HEADERS_SIZE);
+        let mut inputs = Vec::with_capacity(LISTENER_BATCH_SIZE);
+        loop {
+            let count = tokio::select! {
+                count = receive_batch(&rx, &mut batch, &diagnostics) => count,
+                _ = reply_tx.closed() => return,
+            };
+            let count = match count {
+                Some(count) => count,
+                None => return,
+            };
+            for (received, buf) in (0..count).filter_map(|i| batch.get(i)) {
+                if let IpAddr::V4(addr) = received.addr {
+                    inputs.extend(icmp_input(addr, buf, &diagnostics));
+                }
+            }
+            // The batch is only read before sending, as sending may wait
+            for input in inputs.drain(..) {
+                if reply_tx.send(input).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn latency(ongoing: &OngoingRequest, id: &PingIdentifier) -> Duration {
+        if id.stop > ongoing.start {
+            id.stop.duration_since(ongoing.start)
+        } else {
+            Duration::from_nanos(10)
+        }
+    }
+
+    async fn run(self) {
+        let Self {
+            size,
+            mut command_rx,
+            mut reply_rx,
+            listener,
+            mut tx,
+        } = self;
+        let mut index = 0u16;
+        let mut ongoing: BTreeMap<ProbeKey, OngoingRequest> = BTreeMap::new();
+        let mut deadlines = Deadlines::new();
+        let mut last_ttl = 0;
+        let mut done = None;
+
+        while let Some(input) = next_input(
+            &mut command_rx,
+            &mut reply_rx,
+            deadlines.next(),
+            Input::Timeout,
+        )
+        .await
+        {
+            match input {
+                Input::PingRequest(request) => {
+                    let source_port = request.flow_id.wrapping_add(SOURCE_PORT_OFFSET);
+                    let source = match source_address(request.addr, request.port) {
+                        Ok(source) => source,
+                        Err(_) => {
+                            let _ = request
+                                .response_channel
+                                .send(Err(PingError::FailedToSendPacket));
+                            continue;
+                        }
+                    };
+                    // Skip the checksums still used by ongoing probes to the same port
+                    let mut free = false;
+                    for _ in 0..0xFF_FE {
+                        let key = (request.addr, source_port, request.port, 0xFF_FE - index);
+                        free = !ongoing.contains_key(&key);
+                        if free {
+                            break;
+                        }
+                        index = if index == 0xFF_FD { 0 } else { index + 1 };
+                    }
+                    if !free {
+                        let _ = request
+                            .response_channel
+                            .send(Err(PingError::NoFreeIdentifier));
+                        continue;
+                    }
+                    let checksum = 0xFF_FE - index;
+                    let vec = build_datagram(
+                        size,
+                        source,
+                        request.addr,
+                        source_port,
+                        request.port,
+                        checksum,
+                    );
+                    let udp_packet = udp::UdpPacket::new(&vec[..]).unwrap();
+                    let key = (
+                        request.addr,
+                        source_port,
+                        request.port,
+                        udp_packet.get_checksum(),
+                    );
+
+                    if request.ttl != last_ttl {
+                        if tx.set_ttl(request.ttl).is_err() {
+                            let _ = request
+                                .response_channel
+                                .send(Err(PingError::FailedToSendPacket));
+                            continue;
+                        }
+                        last_ttl = request.ttl;
+                    }
+                    if tx.send_to(udp_packet, IpAddr::V4(request.addr)).is_err() {
+                        let _ = request
+                            .response_channel
+                            .send(Err(PingError::FailedToSendPacket));
+                    } else {
+                        let start = Instant::now();
+                        let stop = start + request.timeout;
+                        deadlines.push(stop, key);
+                        ongoing.insert(
+                            key,
+                            OngoingRequest {
+                                start,
+                                stop,
+                                response_channel: request.response_channel,
+                            },
+                        );
+                        if index == 0xFF_FD {
+                            index = 0;
+                        } else {
+                            index += 1;
+                        }
+                    }
+                }
+                Input::PingTimeExceeded(id, mpls) => {
+                    if let Some(ongoing) = ongoing.remove(&id.key()) {
+                        let latency = Self::latency(&ongoing, &id);
+                        let _ = ongoing.response_channel.send(Err(PingError::TimeExceeded {
+                            addr: IpAddr::V4(id.responder),
+                            latency,
+                            ip: IpHeaders {
+                                reply: None,
+                                quoted: id.quoted,
+                            },
+                            mpls,
+                        }));
+                    }
+                }
+                Input::IcmpError { id, code, ty, data } => {
+                    if let Some(ongoing) = ongoing.remove(&id.key()) {
+                        let latency = Self::latency(&ongoing, &id);
+                        // Port unreachable from the target itself: the datagram made it
+                        let reached = ty == icmp::IcmpTypes::DestinationUnreachable
+                            && code
+                                == icmp::destination_unreachable::IcmpCodes::DestinationPortUnreachable
+                            && id.responder == id.destination;
+                        let _ = ongoing.response_channel.send(if reached {
+                            Ok(latency)
+                        } else {
+                            Err(PingError::from_icmp(
+                                IpAddr::V4(id.responder),
+                                ty,
+                                code,
+                                data,
+                                true,
+                                latency,
+                                IpHeaders {
+                                    reply: None,
+                                    quoted: id.quoted,
+                                },
+                            ))
+                        });
+                    }
+                }
+                Input::Timeout => {
+                    let now = Instant::now();
+                    while let Some(key) = deadlines.pop_expired(now) {
+                        // The key may have been reused by a later request
+                        if matches!(ongoing.get(&key), Some(v) if v.stop <= now) {
+                            let v = ongoing.remove(&key).unwrap();
+                            let _ = v
+                                .response_channel
+                                .send(Err(PingError::Timeout { echo: None }));
+                        }
+                    }
+                }
+                Input::Stop(tx) => {
+                    done = Some(tx);
+                    break;
+                }
+            }
+        }
+
+        // Every pinger is gone, or one of them shut the backend down
+        for (_, request) in ongoing {
+            let _ = request.response_channel.send(Err(PingError::BackendClosed));
+        }
+        // The listener returns once the reply channel is closed, and releases its socket
+        drop(reply_rx);
+        let _ = listener.await;
+        drop(tx);
+        if let Some(done) = done {
+            let _ = done.send(());
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Pinger {
+    command_tx: mpsc::Sender<Input>,
+    diagnostics: broadcast::Sender<Diagnostic>,
+}
+
+impl Pinger {
+    // initialize the pinger and start the icmp listener
+    pub fn new(size: u16, parallelism: usize) -> Result<Self, std::io::Error> {
+        let (command_tx, command_rx) = mpsc::channel(parallelism);
+        let (diagnostics, _) = broadcast::channel(super::icmp::DIAGNOSTICS_CAPACITY);
+        PingerBackend::start(size, parallelism, command_rx, diagnostics.clone())?;
+        Ok(Self {
+            command_tx,
+            diagnostics,
+        })
+    }
+
+    // Events from now on, like those of the ICMP pinger
+    pub fn diagnostics(&self) -> broadcast::Receiver<Diagnostic> {
+        self.diagnostics.subscribe()
+    }
+
+    // Send a datagram to addr:port. The flow id selects the source port. Succeeds when the
+    // target answers with an ICMP port unreachable.
+    pub async fn ping(
+        &self,
+        addr: Ipv4Addr,
+        port: u16,
+        ttl: u8,
+        timeout: Duration,
+        flow_id: u16,
+    ) -> Result<Duration, PingError> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .command_tx
+            .send(Input::PingRequest(PingRequest {
+                addr,
+                port,
+                ttl,
+                flow_id,
+                timeout,
+                response_channel: tx,
+            }))
+            .await
+            .is_err()
+        {
+            return Err(PingError::BackendClosed);
+        }
+        rx.await.unwrap_or(Err(PingError::BackendClosed))
+    }
+
+    // Stop the backend, for every clone of the pinger, and wait until its sockets are closed.
+    // Dropping the last clone does the same in the background. Either way, the requests still
+    // waiting fail with `BackendClosed`.
+    pub async fn shutdown(self) {
+        let (tx, rx) = oneshot::channel();
+        if self.command_tx.send(Input::Stop(tx)).await.is_ok() {
+            let _ = rx.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checksum_is_tuned() {
+        let source = Ipv4Addr::new(192, 168, 1, 2);
+        let destination = Ipv4Addr::new(8, 8, 4, 4);
+        for &checksum in &[1, 0x12_34, 0x80_00, 0xFF_FE] {
+            for &size in &[0, 2, 3, 32] {
+                let vec = build_datagram(size, source, destination, 0x80_00, 33434, checksum);
+                let packet = udp::UdpPacket::new(&vec[..]).unwrap();
+                assert_eq!(packet.get_checksum(), checksum);
+                assert_eq!(udp::ipv4_checksum(&packet, &source, &destination), checksum);
+            }
+        }
+    }
+}
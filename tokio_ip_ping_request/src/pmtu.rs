@@ -0,0 +1,265 @@
+use crate::ping::icmp;
+pub use crate::ping::{PingError, ProbeOptions, UnreachableCode};
+use std::future::Future;
+use std::net::IpAddr;
+use std::time::Duration;
+
+// Largest IP packet
+const MAX_MTU: u16 = 0xFF_FF;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pmtu {
+    // Size in bytes of the largest IP packet that reached the destination
+    pub mtu: u16,
+    // Last router that sent a Fragmentation Needed (ICMPv6: Packet Too Big) message, with
+    // the next-hop MTU it gave
+    pub router: Option<IpAddr>,
+    pub reported_mtu: Option<u32>,
+    // Larger probes disappeared without any router telling why
+    pub black_hole: Option<BlackHole>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlackHole {
+    // Smallest IP packet size that disappeared
+    pub size: u16,
+    // First hop answering small probes but not large ones, when one does
+    pub ttl: Option<u8>,
+    pub hop: Option<IpAddr>,
+    // Last hop that answered large probes
+    pub last_answering: Option<IpAddr>,
+}
+
+#[derive(Debug)]
+enum Outcome {
+    Passed,
+    // Did not fit a link, with the router that said so and its next-hop MTU
+    TooBig(Option<(IpAddr, u32)>),
+    Lost,
+}
+
+// Sizes above `low`, which got through, up to `high`, until the largest one that gets
+// through. `probe(size)` sends an IP packet of `size` bytes that must not be fragmented.
+async fn search<F, Fut>(probe: F, mut low: u16, mut high: u16) -> Result<Pmtu, PingError>
+where
+    F: Fn(u16) -> Fut,
+    Fut: Future<Output = Result<Outcome, PingError>>,
+{
+    let mut router = None;
+    let mut reported_mtu = None;
+    let mut lost: Option<u16> = None;
+    // Next-hop MTU to try right away
+    let mut hint = None;
+    while low < high {
+        let size = match hint.take() {
+            Some(size) if size > low && size <= high => size,
+            _ => high - (high - low) / 2,
+        };
+        match probe(size).await? {
+            Outcome::Passed => low = size,
+            Outcome::TooBig(report) => {
+                high = size - 1;
+                if let Some((addr, mtu)) = report {
+                    router = Some(addr);
+                    reported_mtu = Some(mtu);
+                    if mtu < size as u32 {
+                        hint = Some(mtu as u16);
+                    }
+                }
+            }
+            Outcome::Lost => {
+                high = size - 1;
+                lost = Some(lost.map_or(size, |lost| lost.min(size)));
+            }
+        }
+    }
+
+    // Losses above the MTU a router reported are explained by it
+    let black_hole = lost
+        .filter(|size| !matches!(reported_mtu, Some(mtu) if *size as u32 > mtu))
+        .map(|size| BlackHole {
+            size,
+            ttl: None,
+            hop: None,
+            last_answering: None,
+        });
+    Ok(Pmtu {
+        mtu: low,
+        router,
+        reported_mtu,
+        black_hole,
+    })
+}
+
+// Echo request making an IP packet of `size` bytes
+fn probe_options(addr: IpAddr, size: u16, ttl: u8) -> ProbeOptions {
+    let overhead = if addr.is_ipv4() { 20 } else { 40 };
+    ProbeOptions::new()
+        .ttl(ttl)
+        .size(size.saturating_sub(overhead))
+        .dont_fragment(true)
+}
+
+// Send a probe of `size` bytes, retrying on timeouts
+async fn probe(
+    pinger: &icmp::Pinger,
+    addr: IpAddr,
+    size: u16,
+    ttl: u8,
+    timeout: Duration,
+    attempts: u8,
+) -> Result<Outcome, PingError> {
+    let options = probe_options(addr, size, ttl);
+    for _ in 0..attempts.max(1) {
+        match pinger.ping_with_options(addr, options, timeout, 0).await {
+            Ok(_) => return Ok(Outcome::Passed),
+            Err(PingError::Unreachable {
+                code: UnreachableCode::FragmentationNeeded,
+                responder,
+                quoted,
+                ..
+            }) => {
+                let report = quoted.next_hop_mtu.map(|mtu| (responder, mtu));
+                return Ok(Outcome::TooBig(report));
+            }
+            // Above the MTU of the local interface
+            Err(PingError::FailedToSendPacket) => return Ok(Outcome::TooBig(None)),
+            Err(PingError::Timeout { .. }) => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(Outcome::Lost)
+}
+
+// Find the first hop that small probes reach and probes of `size` bytes do not
+async fn locate_black_hole(
+    pinger: &icmp::Pinger,
+    addr: IpAddr,
+    size: u16,
+    min_size: u16,
+    max_ttl: u8,
+    timeout: Duration,
+    attempts: u8,
+) -> BlackHole {
+    let mut black_hole = BlackHole {
+        size,
+        ttl: None,
+        hop: None,
+        last_answering: None,
+    };
+    let send =
+        |size, ttl| pinger.ping_with_options(addr, probe_options(addr, size, ttl), timeout, 0);
+    for ttl in 1..=max_ttl {
+        let mut large = Err(PingError::Timeout { echo: None });
+        for _ in 0..attempts.max(1) {
+            large = send(size, ttl).await;
+            if !matches!(large, Err(PingError::Timeout { .. })) {
+                break;
+            }
+        }
+        match large {
+            Err(PingError::TimeExceeded { addr, .. }) => {
+                black_hole.last_answering = Some(addr);
+                continue;
+            }
+            Err(PingError::Timeout { .. }) => {}
+            // Got through after all, or failed for another reason
+            _ => break,
+        }
+        match send(min_size, ttl).await {
+            Err(PingError::TimeExceeded { addr: hop, .. }) => {
+                black_hole.ttl = Some(ttl);
+                black_hole.hop = Some(hop);
+                break;
+            }
+            Ok(reply) => {
+                black_hole.ttl = Some(ttl);
+                black_hole.hop = Some(reply.responder);
+                break;
+            }
+            // Silent hop
+            Err(PingError::Timeout { .. }) => {}
+            Err(_) => break,
+        }
+    }
+    black_hole
+}
+
+// Largest packet reaching `addr` unfragmented, by sending echo requests with the DF bit set
+pub async fn pmtu_discover(
+    pinger: icmp::Pinger,
+    addr: IpAddr,
+    max_ttl: u8,
+    timeout: Duration,
+    attempts: u8,
+) -> Result<Pmtu, PingError> {
+    // Every link has to carry that much
+    let min_size = if addr.is_ipv4() { 68 } else { 1280 };
+    let pinger = &pinger;
+    match probe(pinger, addr, min_size, max_ttl, timeout, attempts).await? {
+        Outcome::Passed => {}
+        Outcome::Lost => return Err(PingError::Timeout { echo: None }),
+        Outcome::TooBig(_) => return Err(PingError::FailedToSendPacket),
+    }
+    let mut pmtu = search(
+        |size| probe(pinger, addr, size, max_ttl, timeout, attempts),
+        min_size,
+        MAX_MTU,
+    )
+    .await?;
+    if let Some(black_hole) = pmtu.black_hole.as_ref() {
+        let size = black_hole.size;
+        pmtu.black_hole =
+            Some(locate_black_hole(pinger, addr, size, min_size, max_ttl, timeout, attempts).await);
+    }
+    Ok(pmtu)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn search_follows_routers() {
+        let router: IpAddr = "192.0.2.1".parse().unwrap();
+        // A router with a 1400 bytes link, behind a 9000 bytes interface
+        let pmtu = search(
+            |size| async move {
+                Ok(if size > 9000 {
+                    Outcome::TooBig(None)
+                } else if size > 1400 {
+                    Outcome::TooBig(Some((router, 1400)))
+                } else {
+                    Outcome::Passed
+                })
+            },
+            68,
+            MAX_MTU,
+        )
+        .await
+        .unwrap();
+        assert_eq!(pmtu.mtu, 1400);
+        assert_eq!(pmtu.router, Some(router));
+        assert_eq!(pmtu.reported_mtu, Some(1400));
+        assert_eq!(pmtu.black_hole, None);
+
+        // Packets above 1492 bytes disappear
+        let pmtu = search(
+            |size| async move {
+                Ok(if size > 1500 {
+                    Outcome::TooBig(None)
+                } else if size > 1492 {
+                    Outcome::Lost
+                } else {
+                    Outcome::Passed
+                })
+            },
+            68,
+            MAX_MTU,
+        )
+        .await
+        .unwrap();
+        assert_eq!(pmtu.mtu, 1492);
+        assert_eq!(pmtu.black_hole.map(|b| b.size), Some(1493));
+    }
+}
@@ -0,0 +1,447 @@
+use crate::ping::icmp::{self, SocketMode};
+pub use crate::ping::{Diagnostic, PingError, PingReply, ProbeOptions};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::MissedTickBehavior;
+
+// Events waiting for the receiver before the stream stops sending
+const STREAM_CAPACITY: usize = 64;
+// Resolution of the Tokio timers
+pub const MIN_INTERVAL: Duration = Duration::from_millis(1);
+
+#[derive(Debug)]
+pub enum PingEvent {
+    // Answer to the `seq`th echo request, counting from 1
+    Reply {
+        seq: u32,
+        result: Result<PingReply, PingError>,
+    },
+    // Another echo reply to a request that was already answered
+    Duplicate {
+        seq: u32,
+        responder: IpAddr,
+        rtt: Duration,
+    },
+    // Echo reply to a request that timed out, when the pinger still remembered it
+    Late {
+        seq: u32,
+        responder: IpAddr,
+        rtt: Duration,
+    },
+    // Last event of the stream
+    Summary(PingSummary),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RttStatistics {
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+    // Standard deviation, like ping's mdev
+    pub mdev: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PingSummary {
+    pub transmitted: u32,
+    // Echo replies, duplicates excluded
+    pub received: u32,
+    pub duplicates: u32,
+    // Echo replies that came after their request timed out. Not counted as received.
+    pub late: u32,
+    // ICMP errors and failures to send. Timeouts are only losses.
+    pub errors: u32,
+    // None without any echo reply
+    pub rtt: Option<RttStatistics>,
+    // Since the first request
+    pub time: Duration,
+}
+
+impl PingSummary {
+    // Share of the requests left unanswered, in percent
+    pub fn loss(&self) -> f64 {
+        if self.transmitted == 0 {
+            return 0.;
+        }
+        100. * (self.transmitted - self.received) as f64 / self.transmitted as f64
+    }
+}
+
+#[derive(Debug, Default)]
+struct Statistics {
+    received: u32,
+    duplicates: u32,
+    late: u32,
+    errors: u32,
+    min: Option<Duration>,
+    max: Duration,
+    // In seconds
+    sum: f64,
+    square_sum: f64,
+}
+
+impl Statistics {
+    fn add(&mut self, result: &Result<PingReply, PingError>) {
+        match result {
+            Ok(reply) => {
+                self.received += 1;
+                self.min = Some(self.min.map_or(reply.rtt, |min| min.min(reply.rtt)));
+                self.max = self.max.max(reply.rtt);
+                let rtt = reply.rtt.as_secs_f64();
+                self.sum += rtt;
+                self.square_sum += rtt * rtt;
+            }
+            Err(PingError::Timeout { .. }) => {}
+            Err(_) => self.errors += 1,
+        }
+    }
+
+    fn summary(&self, transmitted: u32, time: Duration) -> PingSummary {
+        let rtt = self.min.map(|min| {
+            let avg = self.sum / self.received as f64;
+            let variance = self.square_sum / self.received as f64 - avg * avg;
+            RttStatistics {
+                min,
+                avg: Duration::from_secs_f64(avg),
+                max: self.max,
+                // Rounding can make a null variance slightly negative
+                mdev: Duration::from_secs_f64(variance.max(0.).sqrt()),
+            }
+        });
+        PingSummary {
+            transmitted,
+            received: self.received,
+            duplicates: self.duplicates,
+            late: self.late,
+            errors: self.errors,
+            rtt,
+            time,
+        }
+    }
+}
+
+pub struct PingStream {
+    events: mpsc::Receiver<PingEvent>,
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl PingStream {
+    // None once the summary was received
+    pub async fn next(&mut self) -> Option<PingEvent> {
+        self.events.recv().await
+    }
+
+    // Send no more requests. The summary follows the answers to the ongoing ones.
+    pub fn stop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+// Send an echo request to `addr` every `interval`, `count` times or until stopped, and report
+// the answers as they arrive. Intervals are at least MIN_INTERVAL. Needs a Tokio runtime.
+pub fn ping_stream(
+    pinger: icmp::Pinger,
+    addr: IpAddr,
+    interval: Duration,
+    count: Option<u32>,
+    options: ProbeOptions,
+    timeout: Duration,
+) -> PingStream {
+    let (tx, events) = mpsc::channel(STREAM_CAPACITY);
+    let (stop, stop_rx) = oneshot::channel();
+    let interval = interval.max(MIN_INTERVAL);
+    tokio::spawn(run_stream(
+        pinger, addr, interval, count, options, timeout, stop_rx, tx,
+    ));
+    PingStream {
+        events,
+        stop: Some(stop),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_stream(
+    pinger: icmp::Pinger,
+    addr: IpAddr,
+    interval: Duration,
+    count: Option<u32>,
+    options: ProbeOptions,
+    timeout: Duration,
+    mut stop: oneshot::Receiver<()>,
+    tx: mpsc::Sender<PingEvent>,
+) {
+    let start = Instant::now();
+    // Duplicate and late replies
+    let mut diagnostics = pinger.diagnostics();
+    let (result_tx, mut result_rx) = mpsc::unbounded_channel();
+    let mut ticker = tokio::time::interval(interval);
+    // A late tick does not bring the next ones forward
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut transmitted = 0;
+    let mut pending = 0;
+    let mut stopped = false;
+    // Echo identifier and sequence number, as the pinger tells its requests apart
+    let datagram = pinger.socket_mode() == SocketMode::Datagram;
+    let echo_key = |id, icmp_seq| {
+        if datagram {
+            (0, icmp_seq)
+        } else {
+            (id, icmp_seq)
+        }
+    };
+    // Stream sequence number of the answered and timed out requests, by echo key, and when
+    // they completed. The pinger forgets them after its grace window, so does the stream.
+    let grace_window = pinger.grace_window();
+    let mut answered: HashMap<(u16, u16), (u32, Instant)> = HashMap::new();
+    let mut timed_out: HashMap<(u16, u16), (u32, Instant)> = HashMap::new();
+    let mut statistics = Statistics::default();
+    loop {
+        let sending = !stopped && !matches!(count, Some(count) if transmitted >= count);
+        if !sending && pending == 0 {
+            break;
+        }
+        tokio::select! {
+            _ = ticker.tick(), if sending => {
+                transmitted += 1;
+                pending += 1;
+                let seq = transmitted;
+                let pinger = pinger.clone();
+                let result_tx = result_tx.clone();
+                tokio::spawn(async move {
+                    let result = pinger.ping_with_options(addr, options, timeout, 0).await;
+                    let _ = result_tx.send((seq, result));
+                });
+            }
+            Some((seq, result)) = result_rx.recv() => {
+                pending -= 1;
+                statistics.add(&result);
+                let now = Instant::now();
+                answered.retain(|_, (_, at)| now.duration_since(*at) <= grace_window);
+                timed_out.retain(|_, (_, at)| now.duration_since(*at) <= grace_window);
+                match &result {
+                    Ok(reply) => {
+                        answered.insert(echo_key(reply.id, reply.seq), (seq, now));
+                    }
+                    Err(PingError::Timeout { echo: Some((id, icmp_seq)) }) => {
+                        timed_out.insert(echo_key(*id, *icmp_seq), (seq, now));
+                    }
+                    Err(_) => {}
+                }
+                if tx.send(PingEvent::Reply { seq, result }).await.is_err() {
+                    return;
+                }
+            }
+            diagnostic = diagnostics.recv() => {
+                let event = match diagnostic {
+                    Ok(Diagnostic::DuplicateReply { responder, id, seq: icmp_seq, rtt })
+                        if responder == addr =>
+                    {
+                        match answered.get(&echo_key(id, icmp_seq)) {
+                            Some(&(seq, _)) => {
+                                statistics.duplicates += 1;
+                                PingEvent::Duplicate { seq, responder, rtt }
+                            }
+                            // Another stream's
+                            None => continue,
+                        }
+                    }
+                    Ok(Diagnostic::LateReply { responder, id, seq: icmp_seq, rtt, .. })
+                        if responder == addr =>
+                    {
+                        let key = echo_key(id, icmp_seq);
+                        match timed_out.remove(&key) {
+                            Some(request) => {
+                                statistics.late += 1;
+                                // Its duplicates follow
+                                answered.insert(key, request);
+                                PingEvent::Late { seq: request.0, responder, rtt }
+                            }
+                            // Another stream's
+                            None => continue,
+                        }
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                if tx.send(event).await.is_err() {
+                    return;
+                }
+            }
+            // Also when the stream is dropped
+            _ = &mut stop, if !stopped => stopped = true,
+        }
+    }
+    let summary = statistics.summary(transmitted, start.elapsed());
+    let _ = tx.send(PingEvent::Summary(summary)).await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ping::icmp::PingerOptions;
+    use crate::ping::simulation::{Hop, SimulatedNetwork};
+    use crate::ping::{ClockSource, IpHeaders};
+    use std::time::SystemTime;
+
+    fn reply(rtt_ms: u64) -> Result<PingReply, PingError> {
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+        Ok(PingReply {
+            responder: addr,
+            destination: addr,
+            id: 0,
+            seq: 0,
+            sent_at: SystemTime::now(),
+            received_at: SystemTime::now(),
+            rtt: Duration::from_millis(rtt_ms),
+            clock: ClockSource::UserSpace,
+            payload_len: 56,
+            ip: IpHeaders::default(),
+        })
+    }
+
+    #[test]
+    fn summary_statistics() {
+        let mut statistics = Statistics::default();
+        for result in [
+            reply(10),
+            Err(PingError::Timeout { echo: None }),
+            reply(20),
+            Err(PingError::FailedToSendPacket),
+            reply(30),
+        ] {
+            statistics.add(&result);
+        }
+        statistics.duplicates = 1;
+        let summary = statistics.summary(5, Duration::from_secs(4));
+        assert_eq!(summary.received, 3);
+        assert_eq!(summary.errors, 1);
+        assert_eq!(summary.duplicates, 1);
+        assert!((summary.loss() - 40.).abs() < 1e-9);
+
+        let rtt = summary.rtt.unwrap();
+        assert_eq!(rtt.min, Duration::from_millis(10));
+        assert_eq!(rtt.max, Duration::from_millis(30));
+        assert!((rtt.avg.as_secs_f64() - 0.020).abs() < 1e-9);
+        // sqrt(((10 - 20)² + 0 + (30 - 20)²) / 3) ms
+        assert!((rtt.mdev.as_secs_f64() - 0.008_165).abs() < 1e-6);
+
+        let summary = Statistics::default().summary(0, Duration::ZERO);
+        assert_eq!(summary.loss(), 0.);
+        assert_eq!(summary.rtt, None);
+    }
+
+    async fn events(mut stream: PingStream) -> Vec<PingEvent> {
+        let mut events = vec![];
+        while let Some(event) = stream.next().await {
+            events.push(event);
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn late_replies_find_their_request() {
+        let host: IpAddr = "203.0.113.1".parse().unwrap();
+        let network =
+            SimulatedNetwork::new(1).route(Hop::new(host, Duration::from_millis(10)), vec![]);
+        let pinger = network.pinger(PingerOptions::default());
+        let interval = Duration::from_millis(10);
+        let options = ProbeOptions::new();
+        // Its requests time out before their replies come back
+        let hasty = ping_stream(
+            pinger.clone(),
+            host,
+            interval,
+            Some(5),
+            options,
+            interval / 2,
+        );
+        // Outlives the first one, without timing out
+        let patient = ping_stream(
+            pinger.clone(),
+            host,
+            interval,
+            Some(10),
+            options,
+            Duration::from_secs(1),
+        );
+        let (hasty, patient) = tokio::join!(events(hasty), events(patient));
+
+        let mut timed_out = vec![];
+        let mut late = vec![];
+        for event in hasty {
+            match event {
+                PingEvent::Reply {
+                    seq,
+                    result: Err(PingError::Timeout { .. }),
+                } => timed_out.push(seq),
+                PingEvent::Late { seq, .. } => {
+                    assert!(timed_out.contains(&seq), "{}", seq);
+                    late.push(seq);
+                }
+                PingEvent::Summary(summary) => assert_eq!(summary.late as usize, late.len()),
+                event => panic!("{:?}", event),
+            }
+        }
+        assert_eq!(timed_out, (1..=5).collect::<Vec<_>>());
+        // The last replies come after the end of the stream
+        assert_eq!(late[..2], [1, 2]);
+        for event in patient {
+            match event {
+                PingEvent::Reply { result: Ok(_), .. } => {}
+                PingEvent::Summary(summary) => {
+                    assert_eq!((summary.received, summary.late), (10, 0))
+                }
+                event => panic!("{:?}", event),
+            }
+        }
+        pinger.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn zero_interval_and_duplicates_of_other_hosts() {
+        let host: IpAddr = "203.0.113.1".parse().unwrap();
+        let other: IpAddr = "203.0.113.2".parse().unwrap();
+        let latency = Duration::from_millis(5);
+        let network = SimulatedNetwork::new(1)
+            .route(Hop::new(host, latency), vec![])
+            .route(Hop::new(other, latency).duplicate(1.), vec![]);
+        let pinger = network.pinger(PingerOptions::default());
+        let options = ProbeOptions::new();
+        let timeout = Duration::from_secs(1);
+        let stream = ping_stream(
+            pinger.clone(),
+            host,
+            Duration::ZERO,
+            Some(5),
+            options,
+            timeout,
+        );
+        let duplicated = ping_stream(
+            pinger.clone(),
+            other,
+            MIN_INTERVAL,
+            Some(5),
+            options,
+            timeout,
+        );
+        let (events, duplicated) = tokio::join!(events(stream), events(duplicated));
+
+        for event in events {
+            match event {
+                PingEvent::Reply { result: Ok(_), .. } => {}
+                PingEvent::Summary(summary) => {
+                    assert_eq!((summary.received, summary.duplicates), (5, 0))
+                }
+                event => panic!("{:?}", event),
+            }
+        }
+        assert!(duplicated
+            .iter()
+            .any(|event| matches!(event, PingEvent::Duplicate { .. })));
+        pinger.shutdown().await;
+    }
+}
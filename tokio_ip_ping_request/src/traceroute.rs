@@ -1,48 +1,94 @@
-pub use crate::ping::PingError;
-use crate::ping::{icmp, tcp};
-use std::net::Ipv4Addr;
+use crate::ping::{icmp, udp, ProbeOptions};
+pub use crate::ping::{MplsLabel, PingError, PingReply, UnreachableCode};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr};
 use std::time::Duration;
 use tokio::sync::mpsc;
 
 #[derive(Debug)]
 pub struct RouteNode {
-    pub addr: Ipv4Addr,
+    pub addr: IpAddr,
     pub latency: Duration,
+    // Echo reply of the target, for ICMP traceroutes
+    pub reply: Option<PingReply>,
+    // The node refused to forward or deliver the probe. Ends the route.
+    pub unreachable: Option<UnreachableCode>,
+    // Label stack of the MPLS tunnel the probe expired in
+    pub mpls: Vec<MplsLabel>,
 }
 
+#[derive(Clone, Copy)]
 pub enum FlowId {
     Fixed(u16),
     WithOffset(u8),
 }
 
-pub async fn icmp_traceroute_to_channel(
-    pinger: icmp::Pinger,
-    addr: Ipv4Addr,
-    max_ttl: u8,
-    timeout: Duration,
-    flow_id: FlowId,
-    tx: mpsc::Sender<Result<RouteNode, PingError>>,
-) {
-    for ttl in 1..max_ttl {
-        let flow_id = match flow_id {
+impl FlowId {
+    fn for_ttl(self, ttl: u8) -> u16 {
+        match self {
             FlowId::Fixed(id_shift) => id_shift,
             // TODO This case should never be used. It exists only for testing purposes
             FlowId::WithOffset(offset) => ((offset as u16) << 8) | ttl as u16,
-        };
-        match pinger.ping(addr, ttl, timeout, flow_id).await {
-            Ok(latency) => {
-                if tx.send(Ok(RouteNode { addr, latency })).await.is_err() {
+        }
+    }
+}
+
+// Probe every TTL in turn, with probe(ttl) -> Ok((latency, reply)) once the target is reached
+async fn traceroute_to_channel<F, Fut>(
+    probe: F,
+    addr: IpAddr,
+    max_ttl: u8,
+    tx: mpsc::Sender<Result<RouteNode, PingError>>,
+) where
+    F: Fn(u8) -> Fut,
+    Fut: Future<Output = Result<(Duration, Option<PingReply>), PingError>>,
+{
+    for ttl in 1..max_ttl {
+        match probe(ttl).await {
+            Ok((latency, reply)) => {
+                let node = RouteNode {
+                    addr,
+                    latency,
+                    reply,
+                    unreachable: None,
+                    mpls: vec![],
+                };
+                if tx.send(Ok(node)).await.is_err() {
                     return;
                 }
                 break;
             }
-            Err(PingError::TimeExceeded { addr, latency }) => {
-                if tx.send(Ok(RouteNode { addr, latency })).await.is_err() {
+            Err(PingError::TimeExceeded {
+                addr,
+                latency,
+                mpls,
+                ..
+            }) => {
+                let node = RouteNode {
+                    addr,
+                    latency,
+                    reply: None,
+                    unreachable: None,
+                    mpls,
+                };
+                if tx.send(Ok(node)).await.is_err() {
                     return;
                 }
             }
-            Err(PingError::Timeout) => {
-                let _ = tx.send(Err(PingError::Timeout)).await;
+            Err(PingError::Unreachable {
+                responder,
+                code,
+                latency,
+                ..
+            }) => {
+                let node = RouteNode {
+                    addr: responder,
+                    latency,
+                    reply: None,
+                    unreachable: Some(code),
+                    mpls: vec![],
+                };
+                let _ = tx.send(Ok(node)).await;
                 break;
             }
             Err(error) => {
@@ -53,9 +99,30 @@ pub async fn icmp_traceroute_to_channel(
     }
 }
 
+pub async fn icmp_traceroute_to_channel(
+    pinger: icmp::Pinger,
+    addr: IpAddr,
+    max_ttl: u8,
+    timeout: Duration,
+    flow_id: FlowId,
+    tx: mpsc::Sender<Result<RouteNode, PingError>>,
+) {
+    let pinger = &pinger;
+    // Per flow load balancers hash the checksum of ICMP messages
+    let probe = |ttl| async move {
+        let flow_id = flow_id.for_ttl(ttl);
+        let options = ProbeOptions::new().ttl(ttl).checksum(flow_id);
+        let reply = pinger
+            .ping_with_options(addr, options, timeout, flow_id)
+            .await?;
+        Ok((reply.rtt, Some(reply)))
+    };
+    traceroute_to_channel(probe, addr, max_ttl, tx).await
+}
+
 pub fn icmp_traceroute(
     pinger: icmp::Pinger,
-    addr: Ipv4Addr,
+    addr: IpAddr,
     max_ttl: u8,
     timeout: Duration,
     index: u8,
@@ -74,7 +141,7 @@ pub fn icmp_traceroute(
 
 pub fn paris_icmp_traceroute(
     pinger: icmp::Pinger,
-    addr: Ipv4Addr,
+    addr: IpAddr,
     max_ttl: u8,
     timeout: Duration,
     flow_id: u16,
@@ -90,3 +157,169 @@ pub fn paris_icmp_traceroute(
     ));
     rx
 }
+
+pub enum UdpPort {
+    // Same destination port for every probe
+    Fixed(u16),
+    // Unix traceroute: the destination port grows with the TTL
+    Incrementing(u16),
+}
+
+impl UdpPort {
+    fn for_ttl(&self, ttl: u8) -> u16 {
+        match self {
+            UdpPort::Fixed(port) => *port,
+            UdpPort::Incrementing(base) => base.wrapping_add(ttl as u16 - 1),
+        }
+    }
+}
+
+pub async fn udp_traceroute_to_channel(
+    pinger: udp::Pinger,
+    addr: Ipv4Addr,
+    port: UdpPort,
+    max_ttl: u8,
+    timeout: Duration,
+    flow_id: FlowId,
+    tx: mpsc::Sender<Result<RouteNode, PingError>>,
+) {
+    let (pinger, port) = (&pinger, &port);
+    let probe = |ttl| async move {
+        let latency = pinger
+            .ping(addr, port.for_ttl(ttl), ttl, timeout, flow_id.for_ttl(ttl))
+            .await?;
+        Ok((latency, None))
+    };
+    traceroute_to_channel(probe, IpAddr::V4(addr), max_ttl, tx).await
+}
+
+pub fn udp_traceroute(
+    pinger: udp::Pinger,
+    addr: Ipv4Addr,
+    max_ttl: u8,
+    timeout: Duration,
+    index: u8,
+) -> mpsc::Receiver<Result<RouteNode, PingError>> {
+    let (tx, rx) = mpsc::channel(5);
+    tokio::spawn(udp_traceroute_to_channel(
+        pinger,
+        addr,
+        UdpPort::Incrementing(udp::TRACEROUTE_PORT),
+        max_ttl,
+        timeout,
+        FlowId::WithOffset(index),
+        tx,
+    ));
+    rx
+}
+
+pub fn paris_udp_traceroute(
+    pinger: udp::Pinger,
+    addr: Ipv4Addr,
+    max_ttl: u8,
+    timeout: Duration,
+    flow_id: u16,
+) -> mpsc::Receiver<Result<RouteNode, PingError>> {
+    let (tx, rx) = mpsc::channel(5);
+    tokio::spawn(udp_traceroute_to_channel(
+        pinger,
+        addr,
+        UdpPort::Fixed(udp::TRACEROUTE_PORT),
+        max_ttl,
+        timeout,
+        FlowId::Fixed(flow_id),
+        tx,
+    ));
+    rx
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ping::icmp::PingerOptions;
+    use crate::ping::simulation::{Hop, SimulatedNetwork};
+    use crate::ping::tun;
+
+    fn addr(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    // Addresses of the hops, until the first error
+    async fn hops(mut rx: mpsc::Receiver<Result<RouteNode, PingError>>) -> Vec<IpAddr> {
+        let mut hops = vec![];
+        while let Some(Ok(node)) = rx.recv().await {
+            hops.push(node.addr);
+        }
+        hops
+    }
+
+    // Two paths of two routers to 198.18.1.100
+    fn load_balanced_network() -> SimulatedNetwork {
+        let latency = Duration::from_millis(1);
+        SimulatedNetwork::new(1).route(
+            Hop::new(addr("198.18.1.100"), latency),
+            vec![
+                vec![
+                    Hop::new(addr("198.18.1.11"), latency),
+                    Hop::new(addr("198.18.1.12"), latency),
+                ],
+                vec![
+                    Hop::new(addr("198.18.1.21"), latency),
+                    Hop::new(addr("198.18.1.22"), latency),
+                ],
+            ],
+        )
+    }
+
+    async fn check_load_balanced_paths(pinger: icmp::Pinger) {
+        let timeout = Duration::from_secs(1);
+        let host = addr("198.18.1.100");
+
+        // Paris traceroutes keep to the path of their flow
+        let route = hops(paris_icmp_traceroute(pinger.clone(), host, 10, timeout, 0)).await;
+        assert_eq!(route, vec![addr("198.18.1.11"), addr("198.18.1.12"), host]);
+        let route = hops(paris_icmp_traceroute(pinger.clone(), host, 10, timeout, 1)).await;
+        assert_eq!(route, vec![addr("198.18.1.21"), addr("198.18.1.22"), host]);
+        // Classic ones change flows along the way, and mix the paths
+        let route = hops(icmp_traceroute(pinger.clone(), host, 10, timeout, 0)).await;
+        assert_eq!(route, vec![addr("198.18.1.21"), addr("198.18.1.12"), host]);
+        pinger.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn load_balanced_paths() {
+        let pinger = load_balanced_network().pinger(PingerOptions::default());
+        check_load_balanced_paths(pinger).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs CAP_NET_ADMIN and CAP_NET_RAW"]
+    async fn load_balanced_paths_through_tun() {
+        let network = load_balanced_network();
+        let (tun, pinger) = tun::test_pinger("pingtest1", Ipv4Addr::new(198, 18, 1, 1), network);
+        check_load_balanced_paths(pinger).await;
+        drop(tun);
+    }
+
+    #[tokio::test]
+    async fn silent_hops_end_the_route() {
+        let latency = Duration::from_millis(1);
+        let host = addr("203.0.113.1");
+        let network = SimulatedNetwork::new(1).route(
+            Hop::new(host, latency),
+            vec![vec![
+                Hop::new(addr("198.51.100.1"), latency),
+                Hop::silent(latency),
+            ]],
+        );
+        let pinger = network.pinger(PingerOptions::default());
+        let mut rx = icmp_traceroute(pinger.clone(), host, 10, Duration::from_millis(50), 0);
+        assert_eq!(rx.recv().await.unwrap().unwrap().addr, addr("198.51.100.1"));
+        assert!(matches!(
+            rx.recv().await,
+            Some(Err(PingError::Timeout { .. }))
+        ));
+        assert!(rx.recv().await.is_none());
+        pinger.shutdown().await;
+    }
+}
@@ -1,20 +1,52 @@
 use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio_ip_ping_request::ping;
-use tokio_ip_ping_request::traceroute::{self, PingError, RouteNode};
+use tokio_ip_ping_request::traceroute::{self, MplsLabel, PingError, RouteNode, UnreachableCode};
+
+struct HopSummary {
+    latency: Duration,
+    std_dev: Duration,
+    unreachable: Option<UnreachableCode>,
+    // Last label stack seen
+    mpls: Vec<MplsLabel>,
+}
 
 struct RouteGraph {
-    routes: Vec<HashMap<Ipv4Addr, (Duration, Duration)>>,
+    routes: Vec<HashMap<IpAddr, HopSummary>>,
+}
+
+// Annotations of the classic traceroute
+fn unreachable_marker(code: UnreachableCode) -> &'static str {
+    match code {
+        UnreachableCode::Network => " !N",
+        UnreachableCode::Host => " !H",
+        UnreachableCode::Protocol => " !P",
+        UnreachableCode::Port => " !p",
+        UnreachableCode::FragmentationNeeded => " !F",
+        UnreachableCode::AdminProhibited => " !X",
+    }
 }
 
 impl std::fmt::Display for RouteGraph {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for (i, hop) in self.routes.iter().enumerate() {
             writeln!(f, "Hop {}", i)?;
-            for (ip, (latency, std_dev)) in hop.iter() {
-                writeln!(f, "  - {}: {:?} (+/- {:?})", ip, latency, std_dev)?;
+            for (ip, summary) in hop.iter() {
+                let marker = summary.unreachable.map(unreachable_marker).unwrap_or("");
+                writeln!(
+                    f,
+                    "  - {}: {:?} (+/- {:?}){}",
+                    ip, summary.latency, summary.std_dev, marker
+                )?;
+                for label in summary.mpls.iter() {
+                    writeln!(
+                        f,
+                        "      MPLS Label={} Exp={} S={} TTL={}",
+                        label.label, label.exp, label.bottom as u8, label.ttl
+                    )?;
+                }
             }
         }
         Ok(())
@@ -33,10 +65,16 @@ async fn aggregate_traceroute(
             }
             let route_data = &mut route_data[(ttl - 1) as usize];
             if route_data.get(&ret.addr).is_none() {
-                route_data.insert(ret.addr, vec![]);
+                route_data.insert(ret.addr, (vec![], None, vec![]));
             }
             let route_data = route_data.get_mut(&ret.addr).unwrap();
-            route_data.push(ret.latency);
+            route_data.0.push(ret.latency);
+            if ret.unreachable.is_some() {
+                route_data.1 = ret.unreachable;
+            }
+            if !ret.mpls.is_empty() {
+                route_data.2 = ret.mpls;
+            }
         }
     }
 
@@ -45,7 +83,7 @@ async fn aggregate_traceroute(
             .into_iter()
             .map(|step| {
                 let mut ret = HashMap::new();
-                for (addr, latencies) in step.into_iter() {
+                for (addr, (latencies, unreachable, mpls)) in step.into_iter() {
                     let average =
                         latencies.iter().sum::<Duration>() / latencies.iter().count() as u32;
                     let std_dev = Duration::from_secs_f32(
@@ -64,7 +102,15 @@ async fn aggregate_traceroute(
                         .sqrt()
                             / latencies.iter().count() as f32,
                     );
-                    ret.insert(addr, (average, std_dev));
+                    ret.insert(
+                        addr,
+                        HopSummary {
+                            latency: average,
+                            std_dev,
+                            unreachable,
+                            mpls,
+                        },
+                    );
                 }
                 ret
             })
@@ -72,50 +118,45 @@ async fn aggregate_traceroute(
     }
 }
 
-async fn run_multi_icmp_traceroute(
-    pinger: ping::icmp::Pinger,
-    target: Ipv4Addr,
-    ttl: u8,
-    timeout: Duration,
-    attempts: u8,
-    tx: mpsc::Sender<(u8, Result<RouteNode, PingError>)>,
-) {
-    for i in 0..attempts {
-        let mut rx = traceroute::icmp_traceroute(pinger.clone(), target, ttl, timeout, i);
-        let mut i = 1;
-        while let Some(res) = rx.recv().await {
-            if tx.send((i, res)).await.is_err() {
-                return;
-            }
-            i += 1;
-        }
-    }
+#[derive(Clone, Copy)]
+enum Flavour {
+    Icmp,
+    ParisIcmp,
+    Udp,
+    ParisUdp,
 }
 
-fn multi_icmp_traceroute(
-    pinger: ping::icmp::Pinger,
-    target: Ipv4Addr,
-    ttl: u8,
-    timeout: Duration,
-    attempts: u8,
-) -> mpsc::Receiver<(u8, Result<RouteNode, PingError>)> {
-    let (tx, rx) = mpsc::channel(1);
-    tokio::spawn(run_multi_icmp_traceroute(
-        pinger, target, ttl, timeout, attempts, tx,
-    ));
-    rx
+#[derive(Clone)]
+struct Pingers {
+    icmp: ping::icmp::Pinger,
+    udp: Option<ping::udp::Pinger>,
 }
 
-async fn run_multi_paris_icmp_traceroute(
-    pinger: ping::icmp::Pinger,
-    target: Ipv4Addr,
+async fn run_multi_traceroute(
+    pingers: Pingers,
+    flavour: Flavour,
+    target: IpAddr,
     ttl: u8,
     timeout: Duration,
     attempts: u8,
     tx: mpsc::Sender<(u8, Result<RouteNode, PingError>)>,
 ) {
-    for _ in 0..attempts {
-        let mut rx = traceroute::paris_icmp_traceroute(pinger.clone(), target, ttl, timeout, 0);
+    for attempt in 0..attempts {
+        let mut rx = match (flavour, target, pingers.udp.clone()) {
+            (Flavour::Icmp, _, _) => {
+                traceroute::icmp_traceroute(pingers.icmp.clone(), target, ttl, timeout, attempt)
+            }
+            (Flavour::ParisIcmp, _, _) => {
+                traceroute::paris_icmp_traceroute(pingers.icmp.clone(), target, ttl, timeout, 0)
+            }
+            (Flavour::Udp, IpAddr::V4(target), Some(udp)) => {
+                traceroute::udp_traceroute(udp, target, ttl, timeout, attempt)
+            }
+            (Flavour::ParisUdp, IpAddr::V4(target), Some(udp)) => {
+                traceroute::paris_udp_traceroute(udp, target, ttl, timeout, 0)
+            }
+            _ => return,
+        };
         let mut i = 1;
         while let Some(res) = rx.recv().await {
             if tx.send((i, res)).await.is_err() {
@@ -126,16 +167,17 @@ async fn run_multi_paris_icmp_traceroute(
     }
 }
 
-fn multi_paris_icmp_traceroute(
-    pinger: ping::icmp::Pinger,
-    target: Ipv4Addr,
+fn multi_traceroute(
+    pingers: Pingers,
+    flavour: Flavour,
+    target: IpAddr,
     ttl: u8,
     timeout: Duration,
     attempts: u8,
 ) -> mpsc::Receiver<(u8, Result<RouteNode, PingError>)> {
     let (tx, rx) = mpsc::channel(1);
-    tokio::spawn(run_multi_paris_icmp_traceroute(
-        pinger, target, ttl, timeout, attempts, tx,
+    tokio::spawn(run_multi_traceroute(
+        pingers, flavour, target, ttl, timeout, attempts, tx,
     ));
     rx
 }
@@ -148,43 +190,61 @@ async fn main() {
     let timeout = Duration::from_millis(timeout);
     let ttl: u8 = args.next().unwrap().parse().unwrap();
     let attempts: u8 = args.next().unwrap().parse().unwrap();
-    let pinger = ping::icmp::Pinger::new(64, 20).unwrap();
+    let target: IpAddr = target.parse().unwrap();
+    let pingers = Pingers {
+        icmp: ping::icmp::Pinger::new(64, 20).unwrap(),
+        udp: if target.is_ipv4() {
+            Some(ping::udp::Pinger::new(32, 20).unwrap())
+        } else {
+            None
+        },
+    };
 
-    println!("========================================================");
-    println!("ICMP");
-    println!("========================================================");
+    let mut results = vec![];
+    for &(protocol, flavour, paris_flavour) in &[
+        ("ICMP", Flavour::Icmp, Flavour::ParisIcmp),
+        ("UDP", Flavour::Udp, Flavour::ParisUdp),
+    ] {
+        if protocol == "UDP" && pingers.udp.is_none() {
+            continue;
+        }
+        println!("========================================================");
+        println!("{}", protocol);
+        println!("========================================================");
+
+        println!("--------------------------------------------------------");
+        println!("STD traceroute");
+        println!("--------------------------------------------------------");
+        let res_rx = multi_traceroute(pingers.clone(), flavour, target, ttl, timeout, attempts);
+        let std_res = aggregate_traceroute(res_rx).await;
 
-    println!("--------------------------------------------------------");
-    println!("STD traceroute");
-    println!("--------------------------------------------------------");
-    let res_rx = multi_icmp_traceroute(
-        pinger.clone(),
-        target.parse().unwrap(),
-        ttl,
-        timeout,
-        attempts,
-    );
-    let std_res = aggregate_traceroute(res_rx).await;
-
-    println!("--------------------------------------------------------");
-    println!("Paris traceroute");
-    println!("--------------------------------------------------------");
-    let res_rx = multi_paris_icmp_traceroute(
-        pinger.clone(),
-        target.parse().unwrap(),
-        ttl,
-        timeout,
-        attempts,
-    );
-    let paris_res = aggregate_traceroute(res_rx).await;
+        println!("--------------------------------------------------------");
+        println!("Paris traceroute");
+        println!("--------------------------------------------------------");
+        let res_rx = multi_traceroute(
+            pingers.clone(),
+            paris_flavour,
+            target,
+            ttl,
+            timeout,
+            attempts,
+        );
+        let paris_res = aggregate_traceroute(res_rx).await;
+        results.push((protocol, std_res, paris_res));
+    }
 
     println!("========================================================");
     println!("Results");
     println!("========================================================");
-    println!("STD traceroute");
-    println!("{}\n", std_res);
-    println!("Paris traceroute");
-    println!("{}\n", paris_res);
+    for (protocol, std_res, paris_res) in results.iter() {
+        println!("{} STD traceroute", protocol);
+        println!("{}\n", std_res);
+        println!("{} Paris traceroute", protocol);
+        println!("{}\n", paris_res);
+    }
 
-    pinger.stop().await;
+    pingers.icmp.shutdown().await;
+    if let Some(udp) = pingers.udp {
+        udp.shutdown().await;
+    }
 }